@@ -0,0 +1,189 @@
+use std::cell::OnceCell;
+use std::process;
+
+use anyhow::{Context as _, bail, ensure};
+use itertools::Itertools as _;
+
+use crate::common::quote;
+
+const ANNOTATION_PREFIX: &str = "# sync_install: ";
+
+/// Where the commands are executed
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Profile {
+    Host,
+    Container,
+}
+
+/// What the annotations are evaluated against
+pub struct AnnotationContext {
+    pub profile: Profile,
+    /// Like `x86_64` or `aarch64`
+    pub arch: &'static str,
+    /// Resolved when a hostname condition is evaluated
+    pub hostname: OnceCell<String>,
+}
+
+impl AnnotationContext {
+    /// Context of the running host
+    pub const fn of_host(profile: Profile) -> Self {
+        Self { profile, arch: std::env::consts::ARCH, hostname: OnceCell::new() }
+    }
+    /// Return the hostname given by `uname -n`, which also works outside of Linux.
+    fn hostname(&self) -> anyhow::Result<&str> {
+        if let Some(hostname) = self.hostname.get() {
+            return Ok(hostname);
+        }
+        let output = process::Command::new("uname")
+            .arg("-n")
+            .output()
+            .context("failed to execute \"uname -n\" to get the hostname")?;
+        let status = output.status;
+        ensure!(status.success(), "failed to get the hostname with \"uname -n\": {status}");
+        let hostname = String::from_utf8(output.stdout).context("non-UTF8 hostname")?;
+        Ok(self.hostname.get_or_init(|| hostname.trim().to_owned()))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Annotation<'a> {
+    /// `# sync_install: ignore`
+    Ignore,
+    /// `# sync_install: only=container`
+    Only(Profile),
+    /// `# sync_install: when arch=x86_64` or `# sync_install: when hostname=~dev-*`
+    When(Condition<'a>),
+}
+
+#[derive(Clone, Copy)]
+pub struct Condition<'a> {
+    property: HostProperty,
+    /// With `=~`, the value is a pattern where `*` matches any text and `?` any character.
+    is_pattern: bool,
+    value: &'a str,
+}
+
+#[derive(Clone, Copy)]
+enum HostProperty {
+    Arch,
+    Hostname,
+}
+
+impl Annotation<'_> {
+    /// Without context, like for `lint` and `fmt`, only the ignored lines are not selected.
+    fn selects(self, context: Option<&AnnotationContext>) -> anyhow::Result<bool> {
+        let Some(context) = context else {
+            return Ok(!matches!(self, Self::Ignore));
+        };
+        match self {
+            Self::Ignore => Ok(false),
+            Self::Only(profile) => Ok(context.profile == profile),
+            Self::When(condition) => condition.holds(context),
+        }
+    }
+}
+
+impl Condition<'_> {
+    fn holds(self, context: &AnnotationContext) -> anyhow::Result<bool> {
+        let property_value = match self.property {
+            HostProperty::Arch => context.arch,
+            HostProperty::Hostname => context.hostname()?,
+        };
+        Ok(if self.is_pattern {
+            matches_pattern(self.value.as_bytes(), property_value.as_bytes())
+        } else {
+            property_value == self.value
+        })
+    }
+}
+
+/// Parse a comment line like `# sync_install: ignore`, or return `None` for another line. The
+/// include directives are not annotations.
+pub fn parse_annotation_line(left_trimmed_line: &str) -> Option<anyhow::Result<Annotation<'_>>> {
+    let stripped_line = left_trimmed_line.strip_prefix(ANNOTATION_PREFIX)?.trim();
+    (!stripped_line.starts_with("include ")).then(|| parse_stripped_annotation(stripped_line))
+}
+
+/// Return whether a command line is selected by its annotations: the ones of the preceding lines,
+/// which are consumed, and the inline one, like in `cargo install fsays # sync_install: ignore` in
+/// a `RUN` heredoc.
+pub fn is_selected<'a>(
+    line: &'a str,
+    preceding_annotations: &mut Vec<Annotation<'a>>,
+    context: Option<&AnnotationContext>,
+) -> anyhow::Result<bool> {
+    let inline_annotation = match line.split_once(&format!(" {ANNOTATION_PREFIX}")) {
+        Some((_, stripped_annotation)) => Some(parse_stripped_annotation(stripped_annotation)?),
+        None => None,
+    };
+    // All the annotations are consumed, even after one which does not select the line.
+    let selections: Vec<_> = preceding_annotations
+        .drain(..)
+        .chain(inline_annotation)
+        .map(|annotation| annotation.selects(context))
+        .try_collect()?;
+    Ok(selections.into_iter().all(|selects| selects))
+}
+
+/// Remove the inline annotation of a command in a `RUN` heredoc, with the spaces before it.
+#[must_use]
+pub fn strip_inline_annotation(heredoc_command: &str) -> &str {
+    heredoc_command
+        .split_once(&format!(" {ANNOTATION_PREFIX}"))
+        .map_or(heredoc_command, |(command, _)| command.trim_end_matches(' '))
+}
+
+fn parse_stripped_annotation(stripped_annotation: &str) -> anyhow::Result<Annotation<'_>> {
+    let stripped_annotation = stripped_annotation.trim();
+    if stripped_annotation == "ignore" {
+        return Ok(Annotation::Ignore);
+    }
+    if let Some(condition_str) = stripped_annotation.strip_prefix("when ") {
+        return Ok(Annotation::When(parse_condition(condition_str.trim())?));
+    }
+    let Some(profile_str) = stripped_annotation.strip_prefix("only=") else {
+        bail!("unknown annotation {}", quote(stripped_annotation));
+    };
+    let profile = match profile_str {
+        "host" => Profile::Host,
+        "container" => Profile::Container,
+        _ => bail!("unknown profile {}: expected \"host\" or \"container\"", quote(profile_str)),
+    };
+    Ok(Annotation::Only(profile))
+}
+
+/// Parse `arch=x86_64` or `hostname=~dev-*`.
+fn parse_condition(condition_str: &str) -> anyhow::Result<Condition<'_>> {
+    let Some((property_str, value)) = condition_str.split_once('=') else {
+        bail!("'=' is missing in the condition {}", quote(condition_str));
+    };
+    let property = match property_str {
+        "arch" => HostProperty::Arch,
+        "hostname" => HostProperty::Hostname,
+        _ => bail!(
+            "unknown host property {}: expected \"arch\" or \"hostname\"",
+            quote(property_str)
+        ),
+    };
+    let (is_pattern, value) = match value.strip_prefix('~') {
+        Some(pattern) => (true, pattern),
+        None => (false, value),
+    };
+    if value.is_empty() {
+        bail!("empty value in the condition {}", quote(condition_str));
+    }
+    Ok(Condition { property, is_pattern, value })
+}
+
+/// Match a pattern where `*` matches any text, including an empty one, and `?` any character.
+fn matches_pattern(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', pattern_rest)) => {
+            (0..=text.len()).any(|index| matches_pattern(pattern_rest, &text[index..]))
+        }
+        Some((&pattern_byte, pattern_rest)) => text.split_first().is_some_and(|(&byte, rest)| {
+            (pattern_byte == b'?' || pattern_byte == byte) && matches_pattern(pattern_rest, rest)
+        }),
+    }
+}
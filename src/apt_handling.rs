@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use anyhow::{Context as _, bail, ensure};
+
+use crate::command::{PlannedCommand, command};
+use crate::common::{parse_heredoc_delimiter, quote, strip_command_suffix, strip_heredoc_tabs};
+
+mod nonempty_str_types {
+    crate::nonempty_str::newtype!(AptFilePath, error_msg = "empty APT file path");
+}
+pub use nonempty_str_types::AptFilePath;
+
+#[derive(Clone, Copy)]
+pub struct AptFile<'a>(AptFilePath<'a>);
+
+impl<'a> AptFile<'a> {
+    pub const fn key(self) -> AptFilePath<'a> {
+        self.0
+    }
+}
+
+const SOURCES_DIR: &str = "/etc/apt/sources.list.d/";
+const KEYRING_DIRS: [&str; 2] = ["/usr/share/keyrings/", "/etc/apt/keyrings/"];
+
+/// Return whether the file is a deb822 `.sources` file directly in the sources directory, or a
+/// keyring. A legacy `.list` file is not managed.
+pub fn is_apt_file_path(path: &str) -> bool {
+    let is_sources_file = path
+        .strip_prefix(SOURCES_DIR)
+        .is_some_and(|file_name| file_name.ends_with(".sources") && !file_name.contains('/'));
+    is_sources_file || KEYRING_DIRS.iter().any(|dir| path.starts_with(dir))
+}
+
+/// Parse `COPY <<EOF /etc/apt/sources.list.d/vscodium.sources` followed by the heredoc lines.
+///
+/// Return `None` if the destination is not an APT file. In this case, the heredoc lines are
+/// consumed anyway, so they are not parsed as commands.
+pub fn parse_stripped_line_with_copy_heredoc<'a>(
+    stripped_line: &'a str,
+    next_lines: &mut impl Iterator<Item = &'a str>,
+    apt_map: &mut HashMap<AptFilePath<'a>, String>,
+) -> anyhow::Result<Option<AptFile<'a>>> {
+    let Some((delimiter_str, path_str)) = stripped_line.split_once(' ') else {
+        bail!("heredoc without destination");
+    };
+    let (delimiter, strip_tabs) = parse_heredoc_delimiter(delimiter_str);
+    ensure!(!delimiter.is_empty(), "empty heredoc delimiter");
+    let mut content = String::new();
+    loop {
+        let line = next_lines
+            .next()
+            .with_context(|| format!("missing heredoc delimiter {}", quote(delimiter)))?;
+        let line = strip_heredoc_tabs(line, strip_tabs);
+        if line == delimiter {
+            break;
+        }
+        content.push_str(line);
+        content.push('\n');
+    }
+    if !is_apt_file_path(path_str) {
+        return Ok(None);
+    }
+    insert_apt_file(path_str, content, apt_map).map(Some)
+}
+
+/// Parse `printf 'Types: deb\nURIs: ...\n' > /etc/apt/sources.list.d/vscodium.sources; \`
+pub fn parse_line_with_printf<'a>(
+    left_trimmed_line: &'a str,
+    apt_map: &mut HashMap<AptFilePath<'a>, String>,
+) -> anyhow::Result<AptFile<'a>> {
+    assert_eq!(left_trimmed_line.trim_start(), left_trimmed_line);
+    let expected_suffix = "; \\";
+    let Some(command_str) = strip_command_suffix(left_trimmed_line) else {
+        bail!(
+            "line with \"printf \" to an APT file but which does not end with {}",
+            quote(expected_suffix)
+        );
+    };
+    let (format_str, path_str) = command_str
+        .strip_prefix("printf '")
+        .and_then(|rest| rest.split_once("' > "))
+        .context("expected \"printf 'CONTENT' > PATH\"")?;
+    let content = decode_printf_format(format_str)?;
+    insert_apt_file(path_str, content, apt_map)
+}
+
+fn decode_printf_format(format_str: &str) -> anyhow::Result<String> {
+    let mut content = String::with_capacity(format_str.len());
+    let mut chars = format_str.chars();
+    while let Some(character) = chars.next() {
+        match character {
+            '\\' => match chars.next() {
+                Some('n') => content.push('\n'),
+                Some('t') => content.push('\t'),
+                Some('\\') => content.push('\\'),
+                _ => bail!("unsupported printf escape sequence in {}", quote(format_str)),
+            },
+            '%' => {
+                ensure!(
+                    chars.next() == Some('%'),
+                    "unsupported printf conversion in {}",
+                    quote(format_str)
+                );
+                content.push('%');
+            }
+            _ => content.push(character),
+        }
+    }
+    Ok(content)
+}
+
+fn insert_apt_file<'a>(
+    path_str: &'a str,
+    content: String,
+    apt_map: &mut HashMap<AptFilePath<'a>, String>,
+) -> anyhow::Result<AptFile<'a>> {
+    let path = AptFilePath::from_str(path_str)?;
+    if apt_map.insert(path, content).is_some() {
+        bail!("{} APT file already written in a previous line", quote(path_str));
+    }
+    Ok(AptFile(path))
+}
+
+pub fn compute_apt_file_removal_command<'a>(
+    target_state_apt_map: &HashMap<AptFilePath<'a>, String>,
+    current_state_action: AptFile<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let path = current_state_action.0;
+    (!target_state_apt_map.contains_key(&path)).then(|| {
+        command!["sudo", "rm", "-f", path.as_str()]
+            .unwrap()
+            .with_reason(format!("APT file {} removed from target", path.as_str()))
+    })
+}
+
+pub fn compute_apt_file_write_or_update_command<'a>(
+    current_state_apt_map: &HashMap<AptFilePath<'a>, String>,
+    target_state_apt_map: &HashMap<AptFilePath<'a>, String>,
+    target_state_action: AptFile<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let path = target_state_action.0;
+    // The action comes from the target state so `unwrap()` is OK.
+    let target_state_content = target_state_apt_map.get(&path).unwrap();
+    let current_state_content = current_state_apt_map.get(&path);
+    current_state_content
+        .is_none_or(|current_state_content| current_state_content != target_state_content)
+        .then(|| {
+            let script = "printf '%s' \"$1\" > \"$2\"";
+            let content = target_state_content.clone();
+            let reason = if current_state_content.is_some() {
+                format!("content of the APT file {} changed", path.as_str())
+            } else {
+                format!("APT file {} added to target", path.as_str())
+            };
+            command!["sudo", "sh", "-c", script, "sh", content, path.as_str()]
+                .unwrap()
+                .with_reason(reason)
+        })
+}
+
+/// Plan `apt-get update` if the APT files changed, including the downloaded ones, like a keyring.
+pub fn compute_apt_get_update_command<'a>(
+    current_state_apt_map: &HashMap<AptFilePath<'a>, String>,
+    target_state_apt_map: &HashMap<AptFilePath<'a>, String>,
+    apt_downloads_changed: bool,
+) -> Option<PlannedCommand<'a>> {
+    (apt_downloads_changed || current_state_apt_map != target_state_apt_map)
+        .then(|| command!["sudo", "apt-get", "update"].unwrap().with_reason("APT files changed"))
+}
+
+#[cfg(test)]
+pub fn render_apt_files(
+    apt_map: &HashMap<AptFilePath<'_>, String>,
+    root_dir_path: &std::path::Path,
+) -> anyhow::Result<()> {
+    use std::fs;
+
+    use crate::common::quote_path;
+
+    apt_map.iter().try_for_each(|(path, content)| {
+        let file_path = root_dir_path.join(path.as_str().trim_start_matches('/'));
+        // `file_path` is in `root_dir_path` so `unwrap()` is OK.
+        let dir_path = file_path.parent().unwrap();
+        fs::create_dir_all(dir_path)
+            .with_context(|| format!("failed to create {}", quote_path(dir_path)))?;
+        fs::write(&file_path, content)
+            .with_context(|| format!("failed to write {}", quote_path(&file_path)))
+    })
+}
@@ -1,9 +1,11 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 use anyhow::bail;
 
-use crate::command::{Command, command};
-use crate::common::quote;
+use crate::command::{Command, PlannedCommand, command};
+use crate::common::{quote, strip_command_suffix};
+use crate::formatting::sort_cargo_install_args;
 
 mod crate_name {
     crate::nonempty_str::newtype!(CrateName, error_msg = "empty crate name");
@@ -12,6 +14,16 @@ pub use crate_name::CrateName;
 
 pub struct CargoInstall<'a>(CrateName<'a>, Command<'a>);
 
+impl<'a> CargoInstall<'a> {
+    pub const fn key(&self) -> CrateName<'a> {
+        self.0
+    }
+    /// Assume that the crate installs a binary with the same name, like `pixi`.
+    pub const fn provided_program(&self) -> &'a str {
+        self.0.as_str()
+    }
+}
+
 pub fn parse_line_with_cargo_install<'a>(
     left_trimmed_line: &'a str,
     cargo_map: &mut HashMap<CrateName<'a>, Command<'a>>,
@@ -19,15 +31,16 @@ pub fn parse_line_with_cargo_install<'a>(
     assert_eq!(left_trimmed_line.trim_start(), left_trimmed_line);
     assert!(left_trimmed_line.contains("cargo install "));
     let expected_suffix = "; \\";
-    let Some(command_str) = left_trimmed_line.strip_suffix(expected_suffix) else {
+    let Some(command_str) = strip_command_suffix(left_trimmed_line) else {
         bail!(
             "line with \"cargo install \" but which does not end with {}",
             quote(expected_suffix)
         );
     };
-    // `left_trimmed_line.contains("cargo install ")`` so `unwrap()` is OK.
-    let crate_name_start_index =
-        command_str.find("cargo install ").unwrap() + "cargo_install ".len();
+    // Without the suffix, like an inline annotation, the space after `cargo install` may be gone.
+    let crate_name_start_index = command_str
+        .find("cargo install ")
+        .map_or(command_str.len(), |index| index + "cargo install ".len());
     // `str::split` cannot return an empty iterator so `unwrap()` is OK.
     let crate_name_str = command_str[crate_name_start_index..].split(' ').next().unwrap();
     let crate_name = CrateName::from_str(crate_name_str)?;
@@ -46,21 +59,99 @@ pub fn parse_line_with_cargo_install<'a>(
 pub fn compute_crate_removal_command<'a>(
     target_state_cargo_map: &HashMap<CrateName<'a>, Command<'a>>,
     current_state_action: &CargoInstall<'a>,
-) -> Option<Command<'a>> {
+) -> Option<PlannedCommand<'a>> {
     let crate_name = &current_state_action.0;
-    (!target_state_cargo_map.contains_key(crate_name))
-        .then(|| command!["cargo", "uninstall", crate_name.as_str()].unwrap())
+    (!target_state_cargo_map.contains_key(crate_name)).then(|| {
+        command!["cargo", "uninstall", crate_name.as_str()]
+            .unwrap()
+            .with_reason(format!("crate {} removed from target", crate_name.as_str()))
+    })
 }
 
+/// With a Rust version change, like `["1.97.1", "1.98.0"]`, an unchanged crate is rebuilt.
 pub fn compute_crate_install_or_update_command<'a>(
     current_state_cargo_map: &HashMap<CrateName<'a>, Command<'a>>,
     target_state_action: &CargoInstall<'a>,
-) -> Option<Command<'a>> {
+    rust_version_change: Option<[&str; 2]>,
+) -> Option<PlannedCommand<'a>> {
     let CargoInstall(crate_name, target_state_command) = target_state_action;
     if let Some(current_state_command) = current_state_cargo_map.get(crate_name) {
-        (current_state_command != target_state_command)
-            .then(|| target_state_command.concat_args(std::iter::once("--force")))
+        let reason = if !are_equivalent(current_state_command, target_state_command) {
+            format!(
+                "crate {}: [{}] -> [{}]",
+                crate_name.as_str(),
+                current_state_command.display(),
+                target_state_command.display()
+            )
+        } else if let Some([current_rust_version, target_rust_version]) = rust_version_change {
+            format!(
+                "crate {} rebuilt: Rust {current_rust_version} -> {target_rust_version}",
+                crate_name.as_str()
+            )
+        } else {
+            return None;
+        };
+        Some(target_state_command.concat_args(std::iter::once("--force")).with_reason(reason))
     } else {
-        Some(target_state_command.clone())
+        let reason = format!("crate {} added to target", crate_name.as_str());
+        Some(target_state_command.clone().with_reason(reason))
     }
 }
+
+/// Compare two `cargo install` commands, with their options in the canonical order of `fmt` and
+/// their git source URLs normalized, so that `--git https://github.com/prefix-dev/pixi.git` does
+/// not trigger a needless rebuild of a crate installed with
+/// `--git https://github.com/prefix-dev/pixi`.
+fn are_equivalent(command: &Command, other_command: &Command) -> bool {
+    let (program, _) = command.split_program_and_args();
+    let (other_program, _) = other_command.split_program_and_args();
+    program == other_program
+        && command.precondition() == other_command.precondition()
+        && normalize_git_urls(sorted_args(command))
+            .eq(normalize_git_urls(sorted_args(other_command)))
+}
+
+/// Return the arguments of a `cargo install` command with the ones after `install` sorted.
+fn sorted_args<'a>(command: &'a Command) -> Vec<&'a str> {
+    let (_, args) = command.split_program_and_args();
+    let mut args: Vec<_> = args.collect();
+    let install_arg_count =
+        args.iter().position(|arg| *arg == "install").map_or(0, |index| index + 1);
+    let tail = args.split_off(install_arg_count);
+    // A parsed `cargo install` command has a crate name so `unwrap_or_default()` never applies.
+    args.extend(sort_cargo_install_args(tail.into_iter()).unwrap_or_default());
+    args
+}
+
+fn normalize_git_urls<'a>(
+    args: impl IntoIterator<Item = &'a str>,
+) -> impl Iterator<Item = Cow<'a, str>> {
+    let mut after_git_option = false;
+    args.into_iter().map(move |arg| {
+        let normalized_arg = if after_git_option {
+            Cow::Owned(normalize_git_url(arg))
+        } else if let Some(url) = arg.strip_prefix("--git=") {
+            Cow::Owned(format!("--git={}", normalize_git_url(url)))
+        } else {
+            Cow::Borrowed(arg)
+        };
+        after_git_option = arg == "--git";
+        normalized_arg
+    })
+}
+
+/// Strip a trailing `.git` and trailing slashes, lowercase the host and write `git@host:path` like
+/// `https://host/path`.
+fn normalize_git_url(url: &str) -> String {
+    let (scheme, rest) = if let Some(scp_like_url) = url.strip_prefix("git@") {
+        ("https", Cow::Owned(scp_like_url.replacen(':', "/", 1)))
+    } else if let Some((scheme, rest)) = url.split_once("://") {
+        (scheme, Cow::Borrowed(rest))
+    } else {
+        return url.to_owned();
+    };
+    let (host, path) = rest.split_once('/').unwrap_or((&rest, ""));
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path).trim_end_matches('/');
+    format!("{scheme}://{}/{path}", host.to_lowercase())
+}
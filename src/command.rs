@@ -1,49 +1,225 @@
 // Remark (2024-07-07): the nonempty collections and iterators from the `mitsein` crate look good,
 // but I prefer to avoid to add another dependency right now.
 
+use std::borrow::Cow;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 
-use anyhow::{Context as _, ensure};
+use anyhow::{Context as _, bail, ensure};
+use itertools::Itertools as _;
 
+use crate::common::{expand_tilde, quote};
+
+// Most arguments are borrowed from the `Dockerfile` contents, but a few ones, like the contents of
+// the files to write, are computed. This is why the arguments are `Cow`s.
+//
+// With tilde expansion, the arguments starting with `~/`, like the paths of the downloads, are
+// expanded when the command is executed, like a shell would do, so that an emitted script expands
+// them on the machine which runs it.
 #[derive(Clone, PartialEq, Eq)]
-pub struct Command<'a>(Vec<&'a str>);
+pub struct Command<'a>(Vec<Cow<'a, str>>, Option<Precondition<'a>>, bool);
+
+/// Command computed by comparing the two states, with the reason why it is planned.
+pub struct PlannedCommand<'a> {
+    pub command: Command<'a>,
+    pub reason: String,
+    pub lock: Option<Lock>,
+    /// Program installed or removed by the command, like `pixi` for `cargo install pixi ...`
+    pub provided_program: Option<&'a str>,
+    /// Line of the current state file which declares what the command removes or updates
+    pub current_state_line: Option<SourceLine<'a>>,
+    /// Line of the target state file which declares what the command installs or updates
+    pub target_state_line: Option<SourceLine<'a>>,
+    /// Declarations of the `ENV` instructions of the target state file, resolved when the process
+    /// is started
+    pub environment: Vec<(String, String)>,
+}
+
+/// Line of a `Dockerfile`, with its number starting from 1
+#[derive(Clone, Copy)]
+pub struct SourceLine<'a> {
+    pub number: usize,
+    pub text: &'a str,
+    /// Included file of the line, or `None` for a line of the file given on the command line
+    pub file_path: Option<&'a Path>,
+}
+
+impl<'a> PlannedCommand<'a> {
+    #[must_use]
+    pub fn with_lock(self, lock: Option<Lock>) -> Self {
+        Self { lock, ..self }
+    }
+    #[must_use]
+    pub fn with_provided_program(self, provided_program: Option<&'a str>) -> Self {
+        Self { provided_program, ..self }
+    }
+    #[must_use]
+    pub fn with_source_lines(
+        self,
+        current_state_line: Option<SourceLine<'a>>,
+        target_state_line: Option<SourceLine<'a>>,
+    ) -> Self {
+        Self { current_state_line, target_state_line, ..self }
+    }
+    #[must_use]
+    pub fn with_environment(self, environment: Vec<(String, String)>) -> Self {
+        Self { environment, ..self }
+    }
+    pub fn program(&self) -> &str {
+        self.command.split_program_and_args().0
+    }
+    /// Tell if `self` must be executed before `other` when installing, like
+    /// `cargo install pixi ...` before `pixi global install git=2.55.0`.
+    pub fn provides_program_of(&self, other: &Self) -> bool {
+        self.provided_program.is_some_and(|provided_program| provided_program == other.program())
+    }
+    /// Tell if one of the commands uses the program installed or removed by the other one, so
+    /// that they must keep their order.
+    pub fn must_keep_order_with(&self, other: &Self) -> bool {
+        self.provides_program_of(other) || other.provides_program_of(self)
+    }
+}
+
+/// Resource shared by the commands of a handler: with `--jobs`, two commands with the same lock
+/// are never executed at the same time and keep their order.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Lock {
+    /// `cargo install` and `cargo uninstall` share the install root.
+    CargoInstallRoot,
+    /// `pixi global install` and `pixi global uninstall` share the global manifest.
+    PixiGlobalManifest,
+    /// `git config set --global` and `git config unset --global` share `~/.gitconfig.lock`.
+    GitGlobalConfig,
+    /// A downloaded file is checked after being downloaded.
+    Downloads,
+    /// `apt-get update` reads the APT files, including the downloaded keyrings.
+    Apt,
+    /// Two changes of the same snap conflict.
+    Snap,
+    /// An application may be uninstalled then installed in another scope.
+    Flatpak,
+}
+
+/// Condition checked just before executing the command.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Precondition<'a> {
+    /// The path must be a symbolic link or must not exist, so that a real file is never clobbered.
+    SymlinkOrMissing(Cow<'a, str>),
+}
+
+impl Precondition<'_> {
+    /// Check the condition, with a leading `~/` of the path replaced with the home directory
+    pub fn check(&self, home: Option<&str>) -> anyhow::Result<()> {
+        match self {
+            Self::SymlinkOrMissing(path) => {
+                match fs::symlink_metadata(&*expand_tilde(path, home)) {
+                    Ok(metadata) if !metadata.file_type().is_symlink() => {
+                        bail!("{} exists and is not a symbolic link", quote(path));
+                    }
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                        Err(error).with_context(|| format!("failed to inspect {}", quote(path)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+    /// Same check, as a POSIX shell command which exits on failure
+    pub fn shell_check(&self) -> String {
+        match self {
+            Self::SymlinkOrMissing(path) => {
+                let path_arg = shell_word(path, true);
+                let message = format!("{} exists and is not a symbolic link", quote(path));
+                let message_arg = shell_word(&message, false);
+                format!(
+                    "if [ -e {path_arg} ] && [ ! -L {path_arg} ]; then \
+                    echo {message_arg} >&2; exit 1; fi"
+                )
+            }
+        }
+    }
+}
 
 impl<'a> Command<'a> {
-    fn ensure_invariant(program_and_args: &[&'a str]) -> anyhow::Result<()> {
+    fn ensure_invariant(program_and_args: &[Cow<'a, str>]) -> anyhow::Result<()> {
         let program = program_and_args.first().context("missing program")?;
         ensure!(!program.is_empty(), "empty program");
         Ok(())
     }
-    pub fn from_vec(program_and_args: Vec<&'a str>) -> anyhow::Result<Self> {
+    pub fn from_vec(program_and_args: Vec<Cow<'a, str>>) -> anyhow::Result<Self> {
         Self::ensure_invariant(&program_and_args)?;
-        Ok(Self(program_and_args))
+        Ok(Self(program_and_args, None, false))
     }
     pub fn from_str(program_and_args: &'a str) -> anyhow::Result<Self> {
         // I don't need `shlex::split` for my use case.
-        Self::from_vec(program_and_args.split(' ').collect())
+        Self::from_vec(program_and_args.split(' ').map(Cow::Borrowed).collect())
     }
     #[cfg(test)]
     #[inline]
-    pub fn into_vec(self) -> Vec<&'a str> {
+    pub fn into_vec(self) -> Vec<Cow<'a, str>> {
         self.0
     }
-    pub fn split_program_and_args(&self) -> (&'a str, &[&'a str]) {
+    #[must_use]
+    pub fn with_precondition(self, precondition: Precondition<'a>) -> Self {
+        Self(self.0, Some(precondition), self.2)
+    }
+    #[must_use]
+    pub fn with_tilde_expansion(self) -> Self {
+        Self(self.0, self.1, true)
+    }
+    pub fn with_reason(self, reason: impl Into<String>) -> PlannedCommand<'a> {
+        PlannedCommand {
+            command: self,
+            reason: reason.into(),
+            lock: None,
+            provided_program: None,
+            current_state_line: None,
+            target_state_line: None,
+            environment: Vec::new(),
+        }
+    }
+    pub const fn precondition(&self) -> Option<&Precondition<'a>> {
+        self.1.as_ref()
+    }
+    pub fn split_program_and_args(&self) -> (&str, impl Iterator<Item = &str>) {
         // There is at least one element so `unwrap()` is OK.
         let (program, args) = self.0.split_first().unwrap();
-        (*program, args)
+        (program, args.iter().map(|arg| &**arg))
+    }
+    /// Return the arguments given to the process. With tilde expansion, a leading `~/` is replaced
+    /// with the home directory.
+    pub fn process_args<'b>(&'b self, home: Option<&'b str>) -> impl Iterator<Item = String> {
+        let (_, args) = self.split_program_and_args();
+        args.map(
+            move |arg| if self.2 { expand_tilde(arg, home).into_owned() } else { arg.to_owned() },
+        )
     }
-    pub fn concat_args(&self, args: impl IntoIterator<Item = &'a str>) -> Self {
-        Self(self.0.iter().copied().chain(args).collect())
+    pub fn concat_args(&self, args: impl IntoIterator<Item = impl Into<Cow<'a, str>>>) -> Self {
+        let program_and_args = self.0.iter().cloned().chain(args.into_iter().map(Into::into));
+        Self(program_and_args.collect(), self.1.clone(), self.2)
     }
+    /// Return the command as a line of a POSIX shell, which expands the `~` like `sync_install`.
     pub fn display(&self) -> impl fmt::Display {
-        shlex::try_join(self.0.iter().copied()).unwrap()
+        self.0.iter().map(|arg| shell_word(arg, self.2)).join(" ")
+    }
+}
+
+/// Quote a word for a POSIX shell. With tilde expansion, a leading `~/` is left unquoted so that the
+/// shell expands it.
+fn shell_word(word: &str, expands_tilde: bool) -> String {
+    // `shlex` only fails on nul bytes, which cannot be in a `Dockerfile` line, so `unwrap()` is OK.
+    match word.strip_prefix("~/").filter(|_| expands_tilde) {
+        Some(rest) => format!("~/{}", shlex::try_quote(rest).unwrap()),
+        None => shlex::try_quote(word).unwrap().into_owned(),
     }
 }
 
 #[macro_export]
 macro_rules! command {
     ($($x:expr),+ $(,)?) => {
-        $crate::command::Command::from_vec(std::vec![$($x),+])
+        $crate::command::Command::from_vec(std::vec![$(std::borrow::Cow::from($x)),+])
     };
 }
 pub use command;
@@ -1,13 +1,37 @@
 use std::collections::HashMap;
+use std::iter::Peekable;
+use std::mem;
+use std::path::Path;
 
-use anyhow::Context as _;
+use anyhow::{Context as _, anyhow, bail};
+use itertools::Itertools as _;
 
+use crate::annotation_handling::{AnnotationContext, is_selected, parse_annotation_line};
+use crate::apt_handling::{
+    AptFile, AptFilePath, compute_apt_file_removal_command,
+    compute_apt_file_write_or_update_command, compute_apt_get_update_command, is_apt_file_path,
+    parse_line_with_printf, parse_stripped_line_with_copy_heredoc,
+};
 use crate::cargo_handling::{
     CargoInstall, CrateName, compute_crate_install_or_update_command,
     compute_crate_removal_command, parse_line_with_cargo_install,
 };
-use crate::command::Command;
-use crate::common::quote;
+use crate::command::{Command, Lock, PlannedCommand, SourceLine};
+use crate::common::{
+    parse_heredoc_delimiter, quote, quote_path, strip_command_suffix, strip_heredoc_tabs,
+};
+use crate::download_handling::{
+    DownloadFile, DownloadPath, DownloadSpec, Sha256Check, apt_downloads_differ,
+    compute_download_or_update_command, compute_download_removal_command,
+    compute_sha256_check_command, download_lock, is_download_line, parse_line_with_download,
+    parse_line_with_sha256_check,
+};
+use crate::flatpak_handling::{
+    FlatpakCommitPin, FlatpakInstall, FlatpakRef, FlatpakSpec,
+    compute_flatpak_commit_update_command, compute_flatpak_install_command,
+    compute_flatpak_removal_command, parse_stripped_line_with_flatpak_install,
+    parse_stripped_line_with_flatpak_update,
+};
 use crate::git_handling::{
     GitConfigOption, GitConfigSetGlobal, GitConfigValue, compute_git_global_config_removal_command,
     compute_git_global_config_set_or_update_command,
@@ -17,71 +41,774 @@ use crate::pixi_handling::{
     PixiGlobalInstall, Recipe, RecipeAndVersion, compute_recipe_install_or_update_command,
     compute_recipe_removal_command, parse_stripped_line_with_pixi_global_install,
 };
+use crate::snap_handling::{
+    SnapInstall, SnapName, SnapSpec, compute_snap_install_or_update_command,
+    compute_snap_removal_command, parse_stripped_line_with_snap_install,
+};
+use crate::stage_handling::{BaseImage, FromInstruction, StageName, parse_stripped_line_with_from};
+use crate::symlink_handling::{
+    CreateSymlink, SymlinkDest, SymlinkSource, compute_symlink_creation_or_update_command,
+    compute_symlink_removal_command, has_symbolic_ln_options, parse_stripped_line_with_ln,
+};
 
+#[derive(Default)]
 pub struct State<'a> {
-    ordered_actions: Vec<Action<'a>>,
+    base_image: Option<BaseImage<'a>>,
+    /// Rust version of the base image of the selected stage or, if it is not a `rust` image, of the
+    /// last previous stage with a `rust` image, like a builder stage
+    rust_version: Option<&'a str>,
+    ordered_actions: Vec<(SourceLine<'a>, Action<'a>)>,
+    source_line_map: HashMap<ActionKey<'a>, SourceLine<'a>>,
     cargo_map: HashMap<CrateName<'a>, Command<'a>>,
     pixi_map: HashMap<Recipe<'a>, RecipeAndVersion<'a>>,
     git_map: HashMap<GitConfigOption<'a>, GitConfigValue<'a>>,
+    download_map: HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    apt_map: HashMap<AptFilePath<'a>, String>,
+    snap_map: HashMap<SnapName<'a>, SnapSpec<'a>>,
+    flatpak_map: HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    symlink_map: HashMap<SymlinkDest<'a>, SymlinkSource<'a>>,
+}
+
+impl<'a> State<'a> {
+    /// Numbers of the lines parsed as actions, in file order
+    pub fn action_line_numbers(&self) -> impl Iterator<Item = usize> {
+        self.ordered_actions.iter().map(|(source_line, _)| source_line.number)
+    }
+    /// Merge the state of an included file, as if its actions were at the line of the include
+    /// directive. `file_path` is the path of the including file, used in the error messages.
+    pub fn include(
+        &mut self,
+        file_path: &'a Path,
+        line_number: usize,
+        included_file_path: &'a Path,
+        mut included_state: Self,
+    ) -> anyhow::Result<()> {
+        let position = |source_line: &SourceLine| {
+            format!(
+                "{}:{}",
+                quote_path(source_line.file_path.unwrap_or(file_path)),
+                source_line.number
+            )
+        };
+        for (source_line, _) in &mut included_state.ordered_actions {
+            source_line.file_path.get_or_insert(included_file_path);
+        }
+        for (source_line, action) in &included_state.ordered_actions {
+            if let Some(previous_source_line) = self.source_line_map.get(&action.key()) {
+                bail!(
+                    "[{}] at {} already declared at {}: [{}]",
+                    source_line.text.trim(),
+                    position(source_line),
+                    position(previous_source_line),
+                    previous_source_line.text.trim()
+                );
+            }
+        }
+        let index = self
+            .ordered_actions
+            .iter()
+            .position(|(source_line, _)| {
+                source_line.file_path.is_none() && source_line.number > line_number
+            })
+            .unwrap_or(self.ordered_actions.len());
+        let included_actions = mem::take(&mut included_state.ordered_actions);
+        self.source_line_map.extend(
+            included_actions.iter().map(|(source_line, action)| (action.key(), *source_line)),
+        );
+        self.ordered_actions.splice(index..index, included_actions);
+        self.extend_maps(included_state);
+        Ok(())
+    }
+    /// Layer the state of an overlay file: its actions replace the ones with the same key, at
+    /// their position, and the other ones are appended. A replaced download or flatpak application
+    /// also loses its `sha256sum -c` check or its commit pin, which the overlay can declare again.
+    pub fn overlay(&mut self, overlay_file_path: &'a Path, mut overlay_state: Self) {
+        for (mut source_line, action) in mem::take(&mut overlay_state.ordered_actions) {
+            source_line.file_path.get_or_insert(overlay_file_path);
+            let key = action.key();
+            let dependent_key = action.dependent_key();
+            match self
+                .ordered_actions
+                .iter()
+                .position(|(_, previous_action)| previous_action.key() == key)
+            {
+                Some(index) => {
+                    self.ordered_actions[index] = (source_line, action);
+                    // The check or pin of the overlay, if any, comes after the replacing action.
+                    if let Some(dependent_key) = dependent_key {
+                        self.ordered_actions
+                            .retain(|(_, previous_action)| previous_action.key() != dependent_key);
+                        self.source_line_map.remove(&dependent_key);
+                    }
+                }
+                None => self.ordered_actions.push((source_line, action)),
+            }
+            self.source_line_map.insert(key, source_line);
+        }
+        self.extend_maps(overlay_state);
+    }
+    /// Insert the map entries of the other state, which replace the ones with the same key.
+    fn extend_maps(&mut self, state: Self) {
+        self.cargo_map.extend(state.cargo_map);
+        self.pixi_map.extend(state.pixi_map);
+        self.git_map.extend(state.git_map);
+        self.download_map.extend(state.download_map);
+        self.apt_map.extend(state.apt_map);
+        self.snap_map.extend(state.snap_map);
+        self.flatpak_map.extend(state.flatpak_map);
+        self.symlink_map.extend(state.symlink_map);
+    }
+    /// Write the APT files of the state in `root_dir_path` instead of `/`.
+    #[cfg(test)]
+    pub fn render_apt_files(&self, root_dir_path: &Path) -> anyhow::Result<()> {
+        crate::apt_handling::render_apt_files(&self.apt_map, root_dir_path)
+    }
+}
+
+/// Part of a multi-stage `Dockerfile` which starts with a `FROM` instruction. The lines before the
+/// first `FROM` instruction make up a stage without name.
+pub struct Stage<'a> {
+    name: Option<StageName<'a>>,
+    /// Number of the `FROM` line, or 1 for the lines before the first `FROM`
+    first_line_number: usize,
+    state: State<'a>,
+}
+
+impl Stage<'_> {
+    /// Numbers of the lines parsed as actions, in file order
+    pub fn action_line_numbers(&self) -> impl Iterator<Item = usize> {
+        self.state.action_line_numbers()
+    }
 }
 
 enum Action<'a> {
     CargoInstall(CargoInstall<'a>),
     PixiGlobalInstall(PixiGlobalInstall<'a>),
     GitConfigSetGlobal(GitConfigSetGlobal<'a>),
+    DownloadFile(DownloadFile<'a>),
+    Sha256Check(Sha256Check<'a>),
+    AptFile(AptFile<'a>),
+    SnapInstall(SnapInstall<'a>),
+    FlatpakInstall(FlatpakInstall<'a>),
+    FlatpakCommitPin(FlatpakCommitPin<'a>),
+    CreateSymlink(CreateSymlink<'a>),
+}
+
+/// Identify an action in both states, to find the current state line of a target state action.
+#[derive(PartialEq, Eq, Hash)]
+enum ActionKey<'a> {
+    CargoInstall(CrateName<'a>),
+    PixiGlobalInstall(Recipe<'a>),
+    GitConfigSetGlobal(GitConfigOption<'a>),
+    DownloadFile(DownloadPath<'a>),
+    Sha256Check(DownloadPath<'a>),
+    AptFile(AptFilePath<'a>),
+    SnapInstall(SnapName<'a>),
+    FlatpakInstall(FlatpakRef<'a>),
+    FlatpakCommitPin(FlatpakRef<'a>),
+    CreateSymlink(SymlinkDest<'a>),
+}
+
+impl<'a> Action<'a> {
+    const fn key(&self) -> ActionKey<'a> {
+        match self {
+            Self::CargoInstall(action) => ActionKey::CargoInstall(action.key()),
+            Self::PixiGlobalInstall(action) => ActionKey::PixiGlobalInstall(action.key()),
+            Self::GitConfigSetGlobal(action) => ActionKey::GitConfigSetGlobal(action.key()),
+            Self::DownloadFile(action) => ActionKey::DownloadFile(action.key()),
+            Self::Sha256Check(action) => ActionKey::Sha256Check(action.key()),
+            Self::AptFile(action) => ActionKey::AptFile(action.key()),
+            Self::SnapInstall(action) => ActionKey::SnapInstall(action.key()),
+            Self::FlatpakInstall(action) => ActionKey::FlatpakInstall(action.key()),
+            Self::FlatpakCommitPin(action) => ActionKey::FlatpakCommitPin(action.key()),
+            Self::CreateSymlink(action) => ActionKey::CreateSymlink(action.key()),
+        }
+    }
+    /// Key of the action which depends on this one, like the `sha256sum -c` check of a download
+    const fn dependent_key(&self) -> Option<ActionKey<'a>> {
+        match self {
+            Self::DownloadFile(action) => Some(ActionKey::Sha256Check(action.key())),
+            Self::FlatpakInstall(action) => Some(ActionKey::FlatpakCommitPin(action.key())),
+            _ => None,
+        }
+    }
+    fn lock(&self) -> Option<Lock> {
+        match self {
+            Self::CargoInstall(_) => Some(Lock::CargoInstallRoot),
+            Self::PixiGlobalInstall(_) => Some(Lock::PixiGlobalManifest),
+            Self::GitConfigSetGlobal(_) => Some(Lock::GitGlobalConfig),
+            Self::DownloadFile(action) => Some(download_lock(action.key())),
+            Self::Sha256Check(action) => Some(download_lock(action.key())),
+            Self::AptFile(_) => Some(Lock::Apt),
+            Self::SnapInstall(_) => Some(Lock::Snap),
+            Self::FlatpakInstall(_) | Self::FlatpakCommitPin(_) => Some(Lock::Flatpak),
+            Self::CreateSymlink(_) => None,
+        }
+    }
+    const fn provided_program(&self) -> Option<&'a str> {
+        match self {
+            Self::CargoInstall(action) => Some(action.provided_program()),
+            Self::PixiGlobalInstall(action) => Some(action.provided_program()),
+            _ => None,
+        }
+    }
+}
+
+/// Error of a line which could not be parsed
+pub struct LineError<'a> {
+    pub source_line: SourceLine<'a>,
+    /// Name of the handler which failed to parse the line, like "cargo install"
+    pub handler: &'static str,
+    pub error: anyhow::Error,
+}
+
+impl LineError<'_> {
+    /// Column of the first non-whitespace character, starting from 1. The handlers do not tell
+    /// which word of the line is invalid, so the column is the one of the command.
+    pub fn command_column(&self) -> usize {
+        let text = self.source_line.text;
+        text.chars().take_while(|character| character.is_whitespace()).count() + 1
+    }
+    pub fn into_error(self) -> anyhow::Error {
+        let SourceLine { number, text, .. } = self.source_line;
+        self.error.context(format!(
+            "failed to parse line {number}: {} ({} handler)",
+            quote(text),
+            self.handler
+        ))
+    }
+}
+
+/// Fail if a line is invalid. In this case, the error reports all the invalid lines.
+///
+/// The lines whose annotations do not select the context are skipped. Without context, only the
+/// ignored lines are.
+pub fn parse_stages_from_file_content<'a>(
+    file_content: &'a str,
+    context: Option<&AnnotationContext>,
+) -> anyhow::Result<Vec<Stage<'a>>> {
+    let (stages, line_errors) =
+        parse_stages_and_line_errors_from_file_content(file_content, context);
+    let mut errors: Vec<_> = line_errors.into_iter().map(LineError::into_error).collect();
+    match errors.len() {
+        0 => Ok(stages),
+        // There is one element so `unwrap()` is OK.
+        1 => Err(errors.pop().unwrap()),
+        error_count => bail!(
+            "{error_count} invalid lines:\n{}",
+            errors.iter().map(|error| format!("{error:#}")).join("\n")
+        ),
+    }
+}
+
+/// Return the state of the stage with the given name, or of the last stage. A file without `FROM`
+/// instruction has a single stage, whatever its name.
+pub fn select_stage<'a>(
+    mut stages: Vec<Stage<'a>>,
+    stage_name: Option<&str>,
+) -> anyhow::Result<State<'a>> {
+    let index = match stage_name {
+        Some(stage_name) if stages.len() > 1 => stages
+            .iter()
+            .position(|stage| stage.name.is_some_and(|name| name.matches(stage_name)))
+            .with_context(|| format!("no stage named {}", quote(stage_name)))?,
+        // There is at least one stage so this is OK.
+        _ => stages.len() - 1,
+    };
+    let rust_version = stages[..=index]
+        .iter()
+        .rev()
+        .find_map(|stage| stage.state.base_image.and_then(BaseImage::rust_version));
+    let mut state = stages.swap_remove(index).state;
+    state.rust_version = rust_version;
+    Ok(state)
+}
+
+/// Merge the state of an included file into the stage of the include directive.
+pub fn include_in_stages<'a>(
+    stages: &mut [Stage<'a>],
+    file_path: &'a Path,
+    line_number: usize,
+    included_file_path: &'a Path,
+    included_state: State<'a>,
+) -> anyhow::Result<()> {
+    // The first stage starts at line 1, so `unwrap()` is OK.
+    let stage =
+        stages.iter_mut().rev().find(|stage| stage.first_line_number <= line_number).unwrap();
+    stage.state.include(file_path, line_number, included_file_path, included_state)
+}
+
+/// Parse the file, then select a stage.
+#[cfg(test)]
+pub fn parse_state_from_file_content<'a>(
+    file_content: &'a str,
+    stage_name: Option<&str>,
+    context: Option<&AnnotationContext>,
+) -> anyhow::Result<State<'a>> {
+    select_stage(parse_stages_from_file_content(file_content, context)?, stage_name)
+}
+
+/// Like `parse_stages_from_file_content`, but keep parsing after an invalid line, in order to
+/// report all the invalid lines. In this case, the stages must not be used to compute commands.
+pub fn parse_stages_and_line_errors_from_file_content<'a>(
+    file_content: &'a str,
+    context: Option<&AnnotationContext>,
+) -> (Vec<Stage<'a>>, Vec<LineError<'a>>) {
+    let mut line_errors = Vec::new();
+    // The lines keep their newline, which ends a command in a `RUN` heredoc.
+    let mut lines = (1..).zip(file_content.split_inclusive('\n')).peekable();
+    let state = parse_stage_lines(&mut lines, &mut line_errors, None, context);
+    let mut stages = vec![Stage { name: None, first_line_number: 1, state }];
+    // `parse_stage_lines` stops before a `FROM` line.
+    while let Some((line_number, line)) = lines.next() {
+        let line = strip_newline(line);
+        // The line starts with "FROM " so the slicing is OK.
+        let stripped_line = &line.trim_start()["FROM ".len()..];
+        let previous_stage_names = stages.iter().filter_map(|stage| stage.name);
+        let (base_image, name) =
+            match parse_stripped_line_with_from(stripped_line, previous_stage_names) {
+                Ok(FromInstruction { base_image, stage_name }) => (Some(base_image), stage_name),
+                Err(error) => {
+                    let source_line =
+                        SourceLine { number: line_number, text: line, file_path: None };
+                    line_errors.push(LineError { source_line, handler: "FROM", error });
+                    (None, None)
+                }
+            };
+        let state = parse_stage_lines(&mut lines, &mut line_errors, base_image, context);
+        stages.push(Stage { name, first_line_number: line_number, state });
+    }
+    (stages, line_errors)
+}
+
+/// Remove the newline like `str::lines` does.
+fn strip_newline(line: &str) -> &str {
+    line.strip_suffix("\r\n").or_else(|| line.strip_suffix('\n')).unwrap_or(line)
+}
+
+/// `RUN` heredoc whose lines are being parsed
+#[derive(Clone, Copy)]
+struct RunHeredoc<'a> {
+    delimiter: &'a str,
+    /// With `<<-`, the leading tabs of the lines are removed.
+    strip_tabs: bool,
+    /// Whether the heredoc is the whole command, like in `RUN <<EOF`, so that its lines are shell
+    /// commands. Otherwise, like in `RUN python3 <<EOF`, they are not commands and are skipped.
+    is_script: bool,
 }
 
-pub fn parse_state_from_file_content(file_content: &str) -> anyhow::Result<State<'_>> {
-    let mut ordered_actions = Vec::new();
-    let mut cargo_map = HashMap::new();
-    let mut pixi_map = HashMap::new();
-    let mut git_map = HashMap::new();
-    for (line_number, line) in (1..).zip(file_content.lines()) {
-        let left_trimmed_line = line.trim_start();
-        if left_trimmed_line.bytes().next() == Some(b'#') {
+/// Return the line without indentation, or `None` for a comment, for the first or the last line
+/// of a `RUN` heredoc which is a script, or for a line of another `RUN` heredoc. In a script
+/// heredoc, the line keeps its newline, which ends the command, like the `; \` suffix elsewhere.
+fn left_trimmed_command_line<'a>(
+    line_with_newline: &'a str,
+    run_heredoc: &mut Option<RunHeredoc<'a>>,
+) -> Option<&'a str> {
+    let line = strip_newline(line_with_newline);
+    if let Some(RunHeredoc { delimiter, strip_tabs, is_script }) = *run_heredoc {
+        if strip_heredoc_tabs(line, strip_tabs) == delimiter {
+            *run_heredoc = None;
+            return None;
+        }
+        if !is_script {
+            return None;
+        }
+        let left_trimmed_line = line_with_newline.trim_start();
+        return (!left_trimmed_line.starts_with('#')).then_some(left_trimmed_line);
+    }
+    let left_trimmed_line = line.trim_start();
+    if left_trimmed_line.starts_with('#') {
+        return None;
+    }
+    if let Some(stripped_line) = left_trimmed_line.strip_prefix("RUN ") {
+        let words: Vec<_> = stripped_line.split_whitespace().collect();
+        // `<<<` is a here-string, and the delimiter ends before a redirection, like in
+        // `cat <<EOF>/etc/foo`.
+        let heredoc_word = words.iter().find_map(|word| {
+            let word = word.strip_prefix("<<").filter(|word| !word.starts_with('<'))?;
+            let word = word.split(['<', '>', '|', ';', '&', ')']).next().unwrap_or_default();
+            (!word.is_empty()).then_some(word)
+        });
+        if let Some(word) = heredoc_word {
+            let (delimiter, strip_tabs) = parse_heredoc_delimiter(word);
+            // The `RUN` flags, like `--mount=type=cache,target=/root/.cache`, come before.
+            let is_script = words.iter().skip_while(|word| word.starts_with("--")).count() == 1;
+            *run_heredoc = Some(RunHeredoc { delimiter, strip_tabs, is_script });
+            if is_script {
+                return None;
+            }
+        }
+    }
+    Some(left_trimmed_line)
+}
+
+fn parse_stage_lines<'a>(
+    lines: &mut Peekable<impl Iterator<Item = (usize, &'a str)>>,
+    line_errors: &mut Vec<LineError<'a>>,
+    base_image: Option<BaseImage<'a>>,
+    context: Option<&AnnotationContext>,
+) -> State<'a> {
+    let mut state = State { base_image, ..State::default() };
+    let mut run_heredoc = None;
+    let mut annotations = Vec::new();
+    // First line of the annotations which wait for the next line
+    let mut annotation_line = None;
+    while let Some((line_number, line_with_newline)) = lines.next_if(|(_, next_line)| {
+        run_heredoc.is_some() || !next_line.trim_start().starts_with("FROM ")
+    }) {
+        let line = strip_newline(line_with_newline);
+        let source_line = SourceLine { number: line_number, text: line, file_path: None };
+        // The lines of a heredoc which is not a script, like a Python one, are not annotations.
+        let annotation_result = run_heredoc
+            .is_none_or(|heredoc: RunHeredoc| heredoc.is_script)
+            .then(|| parse_annotation_line(line.trim_start()))
+            .flatten();
+        if let Some(result) = annotation_result {
+            match result {
+                Ok(annotation) => {
+                    annotation_line.get_or_insert(source_line);
+                    annotations.push(annotation);
+                }
+                Err(error) => {
+                    line_errors.push(LineError { source_line, handler: "annotation", error });
+                }
+            }
             continue;
         }
-        (|| {
-            if left_trimmed_line.contains("cargo install ") {
-                let action = parse_line_with_cargo_install(left_trimmed_line, &mut cargo_map)?;
-                ordered_actions.push(Action::CargoInstall(action));
-            } else if let Some(sl) = left_trimmed_line.strip_prefix("pixi global install ") {
-                let action = parse_stripped_line_with_pixi_global_install(sl, &mut pixi_map)?;
-                ordered_actions.push(Action::PixiGlobalInstall(action));
-            } else if let Some(sl) = left_trimmed_line.strip_prefix("git config set --global ") {
-                let action = parse_stripped_line_with_git_config_set_global(sl, &mut git_map)?;
-                ordered_actions.push(Action::GitConfigSetGlobal(action));
+        let command_line = left_trimmed_command_line(line_with_newline, &mut run_heredoc)
+            .and_then(classify_command_line);
+        // The preceding annotations only apply to the next line, which must be a managed command.
+        let Some(command_line) = command_line else {
+            if let Some(source_line) = annotation_line.take() {
+                annotations.clear();
+                line_errors.push(annotation_without_command(source_line));
             }
-            anyhow::Ok(())
-        })()
-        .with_context(|| format!("failed to parse line {line_number}: {}", quote(line)))?;
+            continue;
+        };
+        annotation_line = None;
+        match is_selected(line, &mut annotations, context) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(error) => {
+                line_errors.push(LineError { source_line, handler: "annotation", error });
+                continue;
+            }
+        }
+        if let Err(error) = parse_command_line(command_line, source_line, lines, &mut state) {
+            line_errors.push(LineError { source_line, handler: command_line.handler(), error });
+        }
     }
-    Ok(State { ordered_actions, cargo_map, pixi_map, git_map })
+    line_errors.extend(annotation_line.map(annotation_without_command));
+    state.source_line_map = state
+        .ordered_actions
+        .iter()
+        .map(|(source_line, action)| (action.key(), *source_line))
+        .collect();
+    state
+}
+
+fn annotation_without_command(source_line: SourceLine) -> LineError {
+    let error = anyhow!("the next line is not a command which sync_install manages");
+    LineError { source_line, handler: "annotation", error }
+}
+
+/// Line recognized by a handler, with the text the handler parses
+#[derive(Clone, Copy)]
+enum CommandLine<'a> {
+    CargoInstall(&'a str),
+    PixiGlobalInstall(&'a str),
+    GitConfigSetGlobal(&'a str),
+    Download(&'a str),
+    Sha256Check(&'a str),
+    CopyHeredoc(&'a str),
+    Printf(&'a str),
+    SnapInstall(&'a str),
+    FlatpakInstall(&'a str),
+    FlatpakUpdate(&'a str),
+    Ln(&'a str),
+}
+
+impl CommandLine<'_> {
+    /// Name of the handler, like "cargo install"
+    const fn handler(self) -> &'static str {
+        match self {
+            Self::CargoInstall(_) => "cargo install",
+            Self::PixiGlobalInstall(_) => "pixi global install",
+            Self::GitConfigSetGlobal(_) => "git config",
+            Self::Download(_) => "download",
+            Self::Sha256Check(_) => "sha256sum",
+            Self::CopyHeredoc(_) | Self::Printf(_) => "APT file",
+            Self::SnapInstall(_) => "snap",
+            Self::FlatpakInstall(_) | Self::FlatpakUpdate(_) => "flatpak",
+            Self::Ln(_) => "ln",
+        }
+    }
+}
+
+/// Return the handler which recognizes a line, if any. The other lines are not managed.
+fn classify_command_line(left_trimmed_line: &str) -> Option<CommandLine<'_>> {
+    let line = left_trimmed_line;
+    Some(if line.contains("cargo install ") {
+        CommandLine::CargoInstall(line)
+    } else if let Some(sl) = line.strip_prefix("pixi global install ") {
+        CommandLine::PixiGlobalInstall(sl)
+    } else if let Some(sl) = line.strip_prefix("git config set --global ") {
+        CommandLine::GitConfigSetGlobal(sl)
+    } else if is_download_line(line) {
+        CommandLine::Download(line)
+    } else if line.starts_with("echo ") && line.contains("| sha256sum -c") {
+        CommandLine::Sha256Check(line)
+    } else if let Some(sl) = line.strip_prefix("COPY <<") {
+        CommandLine::CopyHeredoc(sl)
+    } else if line.starts_with("printf ")
+        && strip_command_suffix(line)
+            .unwrap_or(line)
+            .rsplit_once("' > ")
+            .is_some_and(|(_, path)| is_apt_file_path(path))
+    {
+        CommandLine::Printf(line)
+    } else if let Some(sl) = line.strip_prefix("snap install ") {
+        CommandLine::SnapInstall(sl)
+    } else if let Some(sl) = line.strip_prefix("flatpak install ") {
+        CommandLine::FlatpakInstall(sl)
+    } else if let Some(sl) = line.strip_prefix("flatpak update ") {
+        CommandLine::FlatpakUpdate(sl)
+    } else if let Some(sl) = line.strip_prefix("ln ").filter(|sl| has_symbolic_ln_options(sl)) {
+        CommandLine::Ln(sl)
+    } else {
+        return None;
+    })
+}
+
+/// Parse a line with the handler which recognizes it.
+fn parse_command_line<'a>(
+    command_line: CommandLine<'a>,
+    source_line: SourceLine<'a>,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    state: &mut State<'a>,
+) -> anyhow::Result<()> {
+    let action = match command_line {
+        CommandLine::CargoInstall(line) => {
+            Action::CargoInstall(parse_line_with_cargo_install(line, &mut state.cargo_map)?)
+        }
+        CommandLine::PixiGlobalInstall(sl) => Action::PixiGlobalInstall(
+            parse_stripped_line_with_pixi_global_install(sl, &mut state.pixi_map)?,
+        ),
+        CommandLine::GitConfigSetGlobal(sl) => Action::GitConfigSetGlobal(
+            parse_stripped_line_with_git_config_set_global(sl, &mut state.git_map)?,
+        ),
+        CommandLine::Download(line) => {
+            Action::DownloadFile(parse_line_with_download(line, &mut state.download_map)?)
+        }
+        CommandLine::Sha256Check(line) => {
+            Action::Sha256Check(parse_line_with_sha256_check(line, &mut state.download_map)?)
+        }
+        CommandLine::CopyHeredoc(sl) => {
+            let mut next_lines = lines.by_ref().map(|(_, next_line)| strip_newline(next_line));
+            let action =
+                parse_stripped_line_with_copy_heredoc(sl, &mut next_lines, &mut state.apt_map)?;
+            state
+                .ordered_actions
+                .extend(action.map(|action| (source_line, Action::AptFile(action))));
+            return Ok(());
+        }
+        CommandLine::Printf(line) => {
+            Action::AptFile(parse_line_with_printf(line, &mut state.apt_map)?)
+        }
+        CommandLine::SnapInstall(sl) => {
+            Action::SnapInstall(parse_stripped_line_with_snap_install(sl, &mut state.snap_map)?)
+        }
+        CommandLine::FlatpakInstall(sl) => Action::FlatpakInstall(
+            parse_stripped_line_with_flatpak_install(sl, &mut state.flatpak_map)?,
+        ),
+        CommandLine::FlatpakUpdate(sl) => Action::FlatpakCommitPin(
+            parse_stripped_line_with_flatpak_update(sl, &mut state.flatpak_map)?,
+        ),
+        CommandLine::Ln(sl) => {
+            Action::CreateSymlink(parse_stripped_line_with_ln(sl, &mut state.symlink_map)?)
+        }
+    };
+    state.ordered_actions.push((source_line, action));
+    Ok(())
+}
+
+/// Return the base images of both states if they differ.
+pub fn compute_base_image_change<'a>(
+    current_state: &State<'a>,
+    target_state: &State<'a>,
+) -> Option<[BaseImage<'a>; 2]> {
+    let current_base_image = current_state.base_image?;
+    let target_base_image = target_state.base_image?;
+    (current_base_image != target_base_image).then_some([current_base_image, target_base_image])
+}
+
+/// Return the Rust versions of both states if they differ.
+fn compute_rust_version_change<'a>(
+    current_state: &State<'a>,
+    target_state: &State<'a>,
+) -> Option<[&'a str; 2]> {
+    let rust_version_change = [current_state.rust_version?, target_state.rust_version?];
+    (rust_version_change[0] != rust_version_change[1]).then_some(rust_version_change)
 }
 
 // The current crate does not need to be optimized. So the return type of `compute_commands` could
 // have been `Vec<Command>` with another `Command` type with owning strings so without lifetime.
 // But I choosed to use iterators and lifetimes, just because it's more fun that way. :-)
+//
+// With `rebuild_on_base_change`, if the Rust version of the base image changes, the crates are
+// reinstalled with `--force`, even if their `cargo install` line is the same.
 pub fn compute_commands<'a, 'b>(
     current_state: &'b State<'a>,
     target_state: &'b State<'a>,
-) -> impl Iterator<Item = Command<'a>> {
+    rebuild_on_base_change: bool,
+) -> impl Iterator<Item = PlannedCommand<'a>> {
+    let rust_version_change = rebuild_on_base_change
+        .then(|| compute_rust_version_change(current_state, target_state))
+        .flatten();
+    let removal_commands =
+        current_state.ordered_actions.iter().rev().filter_map(|(source_line, action)| {
+            let planned_command = compute_removal_command(current_state, target_state, action);
+            planned_command.map(|planned_command| {
+                planned_command
+                    .with_lock(action.lock())
+                    .with_provided_program(action.provided_program())
+                    .with_source_lines(Some(*source_line), None)
+            })
+        });
+    let install_commands =
+        target_state.ordered_actions.iter().filter_map(|(source_line, action)| {
+            let planned_command = compute_install_or_update_command(
+                current_state,
+                target_state,
+                action,
+                rust_version_change,
+            );
+            planned_command.map(|planned_command| {
+                planned_command
+                    .with_lock(action.lock())
+                    .with_provided_program(action.provided_program())
+                    .with_source_lines(
+                        current_state.source_line_map.get(&action.key()).copied(),
+                        Some(*source_line),
+                    )
+            })
+        });
     itertools::chain![
-        current_state.ordered_actions.iter().rev().filter_map(|action| match action {
-            Action::CargoInstall(action) =>
-                compute_crate_removal_command(&target_state.cargo_map, action),
-            Action::PixiGlobalInstall(action) =>
-                compute_recipe_removal_command(&target_state.pixi_map, *action),
-            Action::GitConfigSetGlobal(action) =>
-                compute_git_global_config_removal_command(&target_state.git_map, *action),
-        }),
-        target_state.ordered_actions.iter().filter_map(|action| match action {
-            Action::CargoInstall(action) =>
-                compute_crate_install_or_update_command(&current_state.cargo_map, action),
-            Action::PixiGlobalInstall(action) =>
-                compute_recipe_install_or_update_command(&current_state.pixi_map, *action),
-            Action::GitConfigSetGlobal(action) =>
-                compute_git_global_config_set_or_update_command(&current_state.git_map, *action),
+        // For example, `pixi global uninstall git` before `cargo uninstall pixi`.
+        order_by_dependencies(removal_commands.collect(), |command, other_command| {
+            other_command.provides_program_of(command)
         }),
+        // For example, `cargo install pixi ...` before `pixi global install git=2.55.0`.
+        order_by_dependencies(install_commands.collect(), PlannedCommand::provides_program_of),
+        compute_apt_get_update_command(
+            &current_state.apt_map,
+            &target_state.apt_map,
+            apt_downloads_differ(&current_state.download_map, &target_state.download_map),
+        )
+        .map(|planned_command| planned_command.with_lock(Some(Lock::Apt))),
     ]
 }
+
+fn compute_removal_command<'a>(
+    current_state: &State<'a>,
+    target_state: &State<'a>,
+    action: &Action<'a>,
+) -> Option<PlannedCommand<'a>> {
+    match action {
+        Action::CargoInstall(action) => {
+            compute_crate_removal_command(&target_state.cargo_map, action)
+        }
+        Action::PixiGlobalInstall(action) => {
+            compute_recipe_removal_command(&target_state.pixi_map, *action)
+        }
+        Action::GitConfigSetGlobal(action) => {
+            compute_git_global_config_removal_command(&target_state.git_map, *action)
+        }
+        Action::DownloadFile(action) => {
+            compute_download_removal_command(&target_state.download_map, action)
+        }
+        Action::AptFile(action) => compute_apt_file_removal_command(&target_state.apt_map, *action),
+        Action::SnapInstall(action) => {
+            compute_snap_removal_command(&target_state.snap_map, *action)
+        }
+        Action::FlatpakInstall(action) => compute_flatpak_removal_command(
+            &current_state.flatpak_map,
+            &target_state.flatpak_map,
+            *action,
+        ),
+        Action::CreateSymlink(action) => {
+            compute_symlink_removal_command(&target_state.symlink_map, *action)
+        }
+        Action::Sha256Check(_) | Action::FlatpakCommitPin(_) => None,
+    }
+}
+
+fn compute_install_or_update_command<'a>(
+    current_state: &State<'a>,
+    target_state: &State<'a>,
+    action: &Action<'a>,
+    rust_version_change: Option<[&str; 2]>,
+) -> Option<PlannedCommand<'a>> {
+    match action {
+        Action::CargoInstall(action) => compute_crate_install_or_update_command(
+            &current_state.cargo_map,
+            action,
+            rust_version_change,
+        ),
+        Action::PixiGlobalInstall(action) => {
+            compute_recipe_install_or_update_command(&current_state.pixi_map, *action)
+        }
+        Action::GitConfigSetGlobal(action) => {
+            compute_git_global_config_set_or_update_command(&current_state.git_map, *action)
+        }
+        Action::DownloadFile(action) => compute_download_or_update_command(
+            &current_state.download_map,
+            &target_state.download_map,
+            action,
+        ),
+        Action::Sha256Check(action) => compute_sha256_check_command(
+            &current_state.download_map,
+            &target_state.download_map,
+            action,
+        ),
+        Action::AptFile(action) => compute_apt_file_write_or_update_command(
+            &current_state.apt_map,
+            &target_state.apt_map,
+            *action,
+        ),
+        Action::SnapInstall(action) => {
+            compute_snap_install_or_update_command(&current_state.snap_map, *action)
+        }
+        Action::FlatpakInstall(action) => compute_flatpak_install_command(
+            &current_state.flatpak_map,
+            &target_state.flatpak_map,
+            *action,
+        ),
+        Action::FlatpakCommitPin(action) => compute_flatpak_commit_update_command(
+            &current_state.flatpak_map,
+            &target_state.flatpak_map,
+            *action,
+        ),
+        Action::CreateSymlink(action) => {
+            compute_symlink_creation_or_update_command(&current_state.symlink_map, *action)
+        }
+    }
+}
+
+/// Stable topological sort: yield the first command which has no remaining command to execute
+/// before it. In case of dependency cycle, yield the first remaining command.
+fn order_by_dependencies<'a>(
+    mut commands: Vec<PlannedCommand<'a>>,
+    must_precede: impl Fn(&PlannedCommand<'a>, &PlannedCommand<'a>) -> bool,
+) -> impl Iterator<Item = PlannedCommand<'a>> {
+    std::iter::from_fn(move || {
+        let index = (0..commands.len())
+            .find(|&index| {
+                !commands.iter().enumerate().any(|(other_index, other_command)| {
+                    other_index != index && must_precede(other_command, &commands[index])
+                })
+            })
+            .unwrap_or(0);
+        (!commands.is_empty()).then(|| commands.remove(index))
+    })
+}
@@ -1,7 +1,10 @@
+use std::borrow::Cow;
 use std::{fmt::Display, path::Path};
 
 use uniquote::Quote as _;
 
+use crate::annotation_handling::strip_inline_annotation;
+
 #[must_use]
 pub fn quote(string: &str) -> impl Display {
     // The Rust documentation says:
@@ -26,3 +29,52 @@ pub fn quote_path(path: &Path) -> impl Display {
     // `format!("{}", path.display())`.
     path.quote()
 }
+
+/// Remove the `; \` suffix of a command in a `RUN` block. Several spaces are allowed before the
+/// backslash, so that the backslashes of a block can be aligned.
+///
+/// In a `RUN` heredoc, like `RUN <<EOF`, a command ends with a newline instead, and the `;` or
+/// `; \` suffix is optional, like an inline `# sync_install: ...` annotation.
+#[must_use]
+pub fn strip_command_suffix(line: &str) -> Option<&str> {
+    if let Some(heredoc_command) = line.strip_suffix('\n') {
+        let heredoc_command = strip_inline_annotation(heredoc_command);
+        let heredoc_command = strip_command_suffix(heredoc_command).unwrap_or(heredoc_command);
+        return Some(heredoc_command.strip_suffix(';').unwrap_or(heredoc_command));
+    }
+    line.strip_suffix(" \\")?.trim_end_matches(' ').strip_suffix(';')
+}
+
+/// Remove the optional quotes around a heredoc delimiter, like in `<<"EOF"` or `<<'EOF'`.
+#[must_use]
+pub fn unquote_heredoc_delimiter(word: &str) -> &str {
+    ['"', '\'']
+        .into_iter()
+        .find_map(|quote_char| word.strip_prefix(quote_char)?.strip_suffix(quote_char))
+        .unwrap_or(word)
+}
+
+/// Parse the word after `<<`, like `EOF`, `"EOF"` or `-EOF`, into the delimiter and whether the
+/// leading tabs of the heredoc lines are removed, which is the case with `<<-`.
+#[must_use]
+pub fn parse_heredoc_delimiter(word: &str) -> (&str, bool) {
+    match word.strip_prefix('-') {
+        Some(word) => (unquote_heredoc_delimiter(word), true),
+        None => (unquote_heredoc_delimiter(word), false),
+    }
+}
+
+/// Remove the leading tabs of a heredoc line, like a shell would do with `<<-`.
+#[must_use]
+pub fn strip_heredoc_tabs(line: &str, strip_tabs: bool) -> &str {
+    if strip_tabs { line.trim_start_matches('\t') } else { line }
+}
+
+/// Replace a leading `~/` with the home directory, like a shell would do.
+#[must_use]
+pub fn expand_tilde<'a>(path: &'a str, home: Option<&str>) -> Cow<'a, str> {
+    match (path.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => Cow::Owned(format!("{}/{rest}", home.trim_end_matches('/'))),
+        _ => Cow::Borrowed(path),
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use anyhow::{Context as _, bail, ensure};
+
+use crate::apt_handling::is_apt_file_path;
+use crate::command::{Command, Lock, PlannedCommand, command};
+use crate::common::{quote, strip_command_suffix};
+
+mod nonempty_str_types {
+    crate::nonempty_str::newtype!(DownloadPath, error_msg = "empty destination path");
+    crate::nonempty_str::newtype!(Sha256, error_msg = "empty SHA-256");
+}
+pub use nonempty_str_types::{DownloadPath, Sha256};
+
+#[derive(PartialEq, Eq)]
+pub struct DownloadSpec<'a> {
+    url: &'a str,
+    sha256: Option<Sha256<'a>>,
+}
+
+pub struct DownloadFile<'a>(DownloadPath<'a>, Command<'a>);
+
+impl<'a> DownloadFile<'a> {
+    pub const fn key(&self) -> DownloadPath<'a> {
+        self.0
+    }
+}
+
+pub struct Sha256Check<'a>(DownloadPath<'a>, Command<'a>);
+
+impl<'a> Sha256Check<'a> {
+    pub const fn key(&self) -> DownloadPath<'a> {
+        self.0
+    }
+}
+
+/// Return whether the line is `curl -fsSL URL -o PATH` or `wget URL -O PATH`, with or without
+/// the `; \` suffix. The other `curl` and `wget` lines, like a piped download, are not managed.
+pub fn is_download_line(left_trimmed_line: &str) -> bool {
+    let command_str = strip_command_suffix(left_trimmed_line).unwrap_or(left_trimmed_line);
+    split_download_command(command_str).is_some()
+}
+
+/// Parse `curl -fsSL URL -o PATH; \` or `wget URL -O PATH; \` where `PATH` is absolute or
+/// starts with `~/`.
+pub fn parse_line_with_download<'a>(
+    left_trimmed_line: &'a str,
+    download_map: &mut HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+) -> anyhow::Result<DownloadFile<'a>> {
+    assert_eq!(left_trimmed_line.trim_start(), left_trimmed_line);
+    let expected_suffix = "; \\";
+    let Some(command_str) = strip_command_suffix(left_trimmed_line) else {
+        bail!("download line which does not end with {}", quote(expected_suffix));
+    };
+    let (url_str, path_str) = split_download_command(command_str).context(
+        "expected \"curl -fsSL URL -o PATH\" or \"wget URL -O PATH\" with a single space between \
+        words",
+    )?;
+    ensure!(!url_str.is_empty(), "empty URL");
+    let path = DownloadPath::from_str(path_str)?;
+    ensure!(
+        path_str.starts_with('/') || path_str.starts_with("~/"),
+        "{} is neither an absolute path nor a path starting with \"~/\"",
+        quote(path_str)
+    );
+    if download_map.insert(path, DownloadSpec { url: url_str, sha256: None }).is_some() {
+        bail!("{} file already downloaded in a previous line", quote(path_str));
+    }
+    // No shell expands the `~` of the path, so `sync_install` does.
+    // The line is left trimmed so `command_str` starts with a non-whitespace so `unwrap()` is OK.
+    let command = Command::from_str(command_str).unwrap().with_tilde_expansion();
+    Ok(DownloadFile(path, command))
+}
+
+/// Return the URL and the path of `curl -fsSL URL -o PATH` or `wget URL -O PATH`.
+fn split_download_command(command_str: &str) -> Option<(&str, &str)> {
+    match command_str.strip_prefix("curl -fsSL ") {
+        Some(rest) => split_url_and_path(rest, "-o"),
+        None => split_url_and_path(command_str.strip_prefix("wget ")?, "-O"),
+    }
+}
+
+fn split_url_and_path<'a>(
+    url_option_and_path: &'a str,
+    option: &str,
+) -> Option<(&'a str, &'a str)> {
+    let mut words = url_option_and_path.split(' ');
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some(url), Some(word), Some(path), None) if word == option => Some((url, path)),
+        _ => None,
+    }
+}
+
+pub fn parse_line_with_sha256_check<'a>(
+    left_trimmed_line: &'a str,
+    download_map: &mut HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+) -> anyhow::Result<Sha256Check<'a>> {
+    assert_eq!(left_trimmed_line.trim_start(), left_trimmed_line);
+    let expected_suffix = "; \\";
+    let Some(command_str) = strip_command_suffix(left_trimmed_line) else {
+        bail!(
+            "line with \"| sha256sum -c\" but which does not end with {}",
+            quote(expected_suffix)
+        );
+    };
+    let sha256_and_path_str = command_str
+        .strip_prefix("echo \"")
+        .and_then(|rest| rest.strip_suffix("\" | sha256sum -c"))
+        .context("expected \"echo \\\"<sha256>  PATH\\\" | sha256sum -c\"")?;
+    let (sha256_str, path_str) = sha256_and_path_str
+        .split_once("  ")
+        .context("missing two spaces between the SHA-256 and the path")?;
+    let sha256 = Sha256::from_str(sha256_str)?;
+    ensure!(
+        sha256_str.len() == 64 && sha256_str.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        "{} is not a SHA-256 in hexadecimal",
+        quote(sha256_str)
+    );
+    let path = DownloadPath::from_str(path_str)?;
+    let spec = download_map
+        .get_mut(&path)
+        .with_context(|| format!("{} file not downloaded in a previous line", quote(path_str)))?;
+    if let Some(previous_sha256) = spec.sha256.replace(sha256) {
+        bail!(
+            "{} file already checked in a previous line: the SHA-256 was {}",
+            quote(path_str),
+            previous_sha256.as_str()
+        );
+    }
+    // `sha256sum -c` reads the checksum from the standard input so a shell is needed. The SHA-256
+    // and the path are given as arguments, so that the shell does not interpret the path.
+    let script = "echo \"$1  $2\" | sha256sum -c";
+    let command =
+        command!["sh", "-c", script, "sh", sha256_str, path_str].unwrap().with_tilde_expansion();
+    Ok(Sha256Check(path, command))
+}
+
+pub fn compute_download_removal_command<'a>(
+    target_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    current_state_action: &DownloadFile<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let path = &current_state_action.0;
+    (!target_state_download_map.contains_key(path)).then(|| {
+        command!["rm", "-f", path.as_str()]
+            .unwrap()
+            .with_tilde_expansion()
+            .with_reason(format!("downloaded file {} removed from target", path.as_str()))
+    })
+}
+
+pub fn compute_download_or_update_command<'a>(
+    current_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    target_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    target_state_action: &DownloadFile<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let DownloadFile(path, command) = target_state_action;
+    download_reason(current_state_download_map, target_state_download_map, *path)
+        .map(|reason| command.clone().with_reason(reason))
+}
+
+pub fn compute_sha256_check_command<'a>(
+    current_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    target_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    target_state_action: &Sha256Check<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let Sha256Check(path, command) = target_state_action;
+    download_reason(current_state_download_map, target_state_download_map, *path).map(|_| {
+        command.clone().with_reason(format!("check of the downloaded file {}", path.as_str()))
+    })
+}
+
+/// Return the lock of the download or of the check of a file. A keyring is an APT file, which is
+/// downloaded before `apt-get update`.
+pub fn download_lock(path: DownloadPath<'_>) -> Lock {
+    if is_apt_file_path(path.as_str()) { Lock::Apt } else { Lock::Downloads }
+}
+
+/// Tell if the downloads into the APT directories, like the keyrings, differ between both states,
+/// so that `apt-get update` is needed.
+pub fn apt_downloads_differ<'a>(
+    current_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    target_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+) -> bool {
+    !includes_apt_downloads(current_state_download_map, target_state_download_map)
+        || !includes_apt_downloads(target_state_download_map, current_state_download_map)
+}
+
+fn includes_apt_downloads<'a>(
+    download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    other_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+) -> bool {
+    other_download_map
+        .iter()
+        .filter(|(path, _)| is_apt_file_path(path.as_str()))
+        .all(|(path, spec)| download_map.get(path) == Some(spec))
+}
+
+fn download_reason<'a>(
+    current_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    target_state_download_map: &HashMap<DownloadPath<'a>, DownloadSpec<'a>>,
+    path: DownloadPath<'a>,
+) -> Option<String> {
+    // The action comes from the target state so `unwrap()` is OK.
+    let target_state_spec = target_state_download_map.get(&path).unwrap();
+    match current_state_download_map.get(&path) {
+        None => Some(format!("downloaded file {} added to target", path.as_str())),
+        Some(current_state_spec) if current_state_spec.url != target_state_spec.url => Some(
+            format!("{}: {} -> {}", path.as_str(), current_state_spec.url, target_state_spec.url),
+        ),
+        Some(current_state_spec) if current_state_spec != target_state_spec => {
+            Some(format!("SHA-256 of {} changed", path.as_str()))
+        }
+        Some(_) => None,
+    }
+}
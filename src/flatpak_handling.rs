@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use anyhow::{Context as _, bail, ensure};
+
+use crate::command::{PlannedCommand, command};
+use crate::common::{quote, strip_command_suffix};
+
+mod nonempty_str_types {
+    crate::nonempty_str::newtype!(FlatpakRemote, error_msg = "empty flatpak remote");
+    crate::nonempty_str::newtype!(FlatpakAppId, error_msg = "empty flatpak application id");
+    crate::nonempty_str::newtype!(FlatpakCommit, error_msg = "empty flatpak commit");
+}
+pub use nonempty_str_types::{FlatpakAppId, FlatpakCommit, FlatpakRemote};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlatpakRef<'a> {
+    remote: FlatpakRemote<'a>,
+    app_id: FlatpakAppId<'a>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlatpakScope {
+    #[default]
+    System,
+    User,
+}
+
+impl FlatpakScope {
+    const fn option(self) -> &'static str {
+        match self {
+            Self::System => "--system",
+            Self::User => "--user",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FlatpakSpec<'a> {
+    scope: FlatpakScope,
+    commit: Option<FlatpakCommit<'a>>,
+}
+
+#[derive(Clone, Copy)]
+pub struct FlatpakInstall<'a>(FlatpakRef<'a>);
+
+impl<'a> FlatpakInstall<'a> {
+    pub const fn key(self) -> FlatpakRef<'a> {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FlatpakCommitPin<'a>(FlatpakRef<'a>);
+
+impl<'a> FlatpakCommitPin<'a> {
+    pub const fn key(self) -> FlatpakRef<'a> {
+        self.0
+    }
+}
+
+struct ParsedWords<'a> {
+    scope: Option<FlatpakScope>,
+    commit: Option<FlatpakCommit<'a>>,
+    positional_words: Vec<&'a str>,
+}
+
+fn parse_words(words_str: &str, with_commit: bool) -> anyhow::Result<ParsedWords<'_>> {
+    let mut scope = None;
+    let mut commit = None;
+    let mut assume_yes = false;
+    let mut positional_words = Vec::new();
+    for word in words_str.split(' ') {
+        match word {
+            "-y" | "--assumeyes" | "--noninteractive" => assume_yes = true,
+            "--system" | "--user" => {
+                let new_scope =
+                    if word == "--user" { FlatpakScope::User } else { FlatpakScope::System };
+                ensure!(scope.is_none_or(|old_scope| old_scope == new_scope), "conflicting scopes");
+                scope = Some(new_scope);
+            }
+            _ if with_commit && word.starts_with("--commit=") => {
+                ensure!(commit.is_none(), "--commit given twice");
+                commit = Some(FlatpakCommit::from_str(&word["--commit=".len()..])?);
+            }
+            _ if word.starts_with('-') => bail!("unsupported flatpak option: {}", quote(word)),
+            _ => positional_words.push(word),
+        }
+    }
+    ensure!(assume_yes, "missing -y: the command would wait for a confirmation");
+    Ok(ParsedWords { scope, commit, positional_words })
+}
+
+/// Parse `flatpak install -y [--user|--system] REMOTE APP_ID; \`
+pub fn parse_stripped_line_with_flatpak_install<'a>(
+    stripped_line: &'a str,
+    flatpak_map: &mut HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+) -> anyhow::Result<FlatpakInstall<'a>> {
+    let expected_suffix = "; \\";
+    let Some(words_str) = strip_command_suffix(stripped_line) else {
+        bail!(
+            "line with \"flatpak install \" but which does not end with {}",
+            quote(expected_suffix)
+        );
+    };
+    let ParsedWords { scope, positional_words, .. } = parse_words(words_str, false)?;
+    let [remote_str, app_id_str] = positional_words[..] else {
+        bail!("expected a remote and an application id");
+    };
+    let flatpak_ref = FlatpakRef {
+        remote: FlatpakRemote::from_str(remote_str)?,
+        app_id: FlatpakAppId::from_str(app_id_str)?,
+    };
+    let spec = FlatpakSpec { scope: scope.unwrap_or_default(), commit: None };
+    if flatpak_map.insert(flatpak_ref, spec).is_some() {
+        bail!(
+            "{} flatpak application from {} already installed in a previous line",
+            quote(app_id_str),
+            quote(remote_str)
+        );
+    }
+    Ok(FlatpakInstall(flatpak_ref))
+}
+
+/// Parse `flatpak update -y [--user|--system] --commit=COMMIT APP_ID; \`
+pub fn parse_stripped_line_with_flatpak_update<'a>(
+    stripped_line: &'a str,
+    flatpak_map: &mut HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+) -> anyhow::Result<FlatpakCommitPin<'a>> {
+    let expected_suffix = "; \\";
+    let Some(words_str) = strip_command_suffix(stripped_line) else {
+        bail!(
+            "line with \"flatpak update \" but which does not end with {}",
+            quote(expected_suffix)
+        );
+    };
+    let ParsedWords { scope, commit, positional_words } = parse_words(words_str, true)?;
+    let commit = commit.context("missing --commit")?;
+    let [app_id_str] = positional_words[..] else {
+        bail!("expected a single application id");
+    };
+    let app_id = FlatpakAppId::from_str(app_id_str)?;
+    // The application id does not tell the remote, so it must be installed from a single one.
+    let mut matching_entries =
+        flatpak_map.iter_mut().filter(|(flatpak_ref, _)| flatpak_ref.app_id == app_id);
+    let (flatpak_ref, spec) = matching_entries.next().with_context(|| {
+        format!("{} flatpak application not installed in a previous line", quote(app_id_str))
+    })?;
+    ensure!(
+        matching_entries.next().is_none(),
+        "{} flatpak application installed from several remotes in previous lines",
+        quote(app_id_str)
+    );
+    ensure!(
+        scope.unwrap_or_default() == spec.scope,
+        "{} flatpak application installed with another scope",
+        quote(app_id_str)
+    );
+    if let Some(previous_commit) = spec.commit.replace(commit) {
+        bail!(
+            "{} flatpak application already pinned in a previous line: the commit was {}",
+            quote(app_id_str),
+            previous_commit.as_str()
+        );
+    }
+    Ok(FlatpakCommitPin(*flatpak_ref))
+}
+
+pub fn compute_flatpak_removal_command<'a>(
+    current_state_flatpak_map: &HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    target_state_flatpak_map: &HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    current_state_action: FlatpakInstall<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let flatpak_ref = current_state_action.0;
+    // The action comes from the current state so `unwrap()` is OK.
+    let current_state_scope = current_state_flatpak_map.get(&flatpak_ref).unwrap().scope;
+    let scope_option = current_state_scope.option();
+    let app_id = flatpak_ref.app_id.as_str();
+    let reason = match target_state_flatpak_map.get(&flatpak_ref) {
+        None => format!("flatpak application {app_id} removed from target"),
+        // When the scope changes, the application is uninstalled from the previous scope.
+        Some(target_state_spec) if target_state_spec.scope != current_state_scope => format!(
+            "flatpak application {app_id}: {scope_option} -> {}",
+            target_state_spec.scope.option()
+        ),
+        Some(_) => return None,
+    };
+    Some(command!["flatpak", "uninstall", "-y", scope_option, app_id].unwrap().with_reason(reason))
+}
+
+pub fn compute_flatpak_install_command<'a>(
+    current_state_flatpak_map: &HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    target_state_flatpak_map: &HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    target_state_action: FlatpakInstall<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let flatpak_ref = target_state_action.0;
+    // The action comes from the target state so `unwrap()` is OK.
+    let target_state_spec = target_state_flatpak_map.get(&flatpak_ref).unwrap();
+    let scope_option = target_state_spec.scope.option();
+    let FlatpakRef { remote, app_id } = flatpak_ref;
+    match current_state_flatpak_map.get(&flatpak_ref) {
+        Some(current_state_spec) if current_state_spec.scope == target_state_spec.scope => {
+            // When the commit is no longer pinned, the application is updated to the latest one.
+            (current_state_spec.commit.is_some() && target_state_spec.commit.is_none()).then(|| {
+                command!["flatpak", "update", "-y", scope_option, app_id.as_str()]
+                    .unwrap()
+                    .with_reason(format!("flatpak application {} unpinned", app_id.as_str()))
+            })
+        }
+        _ => Some(
+            command!["flatpak", "install", "-y", scope_option, remote.as_str(), app_id.as_str()]
+                .unwrap()
+                .with_reason(format!(
+                    "flatpak application {} {scope_option} added to target",
+                    app_id.as_str()
+                )),
+        ),
+    }
+}
+
+pub fn compute_flatpak_commit_update_command<'a>(
+    current_state_flatpak_map: &HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    target_state_flatpak_map: &HashMap<FlatpakRef<'a>, FlatpakSpec<'a>>,
+    target_state_action: FlatpakCommitPin<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let flatpak_ref = target_state_action.0;
+    // The action comes from the target state so `unwrap()` is OK.
+    let target_state_spec = target_state_flatpak_map.get(&flatpak_ref).unwrap();
+    // The commit is pinned by the `flatpak update --commit=` line so `unwrap()` is OK.
+    let commit = target_state_spec.commit.unwrap();
+    current_state_flatpak_map
+        .get(&flatpak_ref)
+        .is_none_or(|current_state_spec| current_state_spec != target_state_spec)
+        .then(|| {
+            let scope_option = target_state_spec.scope.option();
+            let commit_option = format!("--commit={}", commit.as_str());
+            let app_id = flatpak_ref.app_id.as_str();
+            command!["flatpak", "update", "-y", scope_option, commit_option, app_id]
+                .unwrap()
+                .with_reason(format!("flatpak application {app_id} pinned to {}", commit.as_str()))
+        })
+}
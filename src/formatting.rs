@@ -0,0 +1,192 @@
+use std::borrow::Cow;
+
+use anyhow::Context as _;
+
+use crate::annotation_handling::{parse_annotation_line, strip_inline_annotation};
+use crate::command_computing::{Stage, parse_stages_from_file_content};
+use crate::common::strip_command_suffix;
+
+/// Rank of the `cargo install` options in the canonical order. The other options are between the
+/// features and `--locked`.
+const CARGO_INSTALL_OPTION_RANKS: [(&str, u8); 16] = [
+    ("--version", 0),
+    ("--git", 1),
+    ("--branch", 2),
+    ("--tag", 2),
+    ("--rev", 2),
+    ("--path", 3),
+    ("--registry", 4),
+    ("--index", 4),
+    ("--features", 5),
+    ("-F", 5),
+    ("--all-features", 5),
+    ("--no-default-features", 5),
+    ("--bin", 6),
+    ("--bins", 6),
+    ("--locked", 8),
+    ("--force", 9),
+];
+const OTHER_CARGO_INSTALL_OPTION_RANK: u8 = 7;
+
+/// `cargo install` options followed by a value, when the value is not after a `=`
+const CARGO_INSTALL_OPTIONS_WITH_VALUE: [&str; 17] = [
+    "--version",
+    "--git",
+    "--branch",
+    "--tag",
+    "--rev",
+    "--path",
+    "--registry",
+    "--index",
+    "--features",
+    "-F",
+    "--bin",
+    "--example",
+    "--root",
+    "--target",
+    "--target-dir",
+    "--profile",
+    "--jobs",
+];
+
+/// Rewrite the managed lines in a canonical format:
+///
+/// - the options of `cargo install` are in a canonical order,
+/// - consecutive `git config set --global` lines are sorted by option,
+/// - the backslashes at the end of the managed lines of a `RUN` block are aligned.
+///
+/// The comments and the other lines are untouched. Formatting twice changes nothing.
+pub fn format_file_content(file_content: &str) -> anyhow::Result<String> {
+    let stages = parse_stages_from_file_content(file_content, None)
+        .context("only a valid file can be formatted")?;
+    // The line numbers start from 1.
+    let managed_indexes: Vec<_> = stages
+        .iter()
+        .flat_map(Stage::action_line_numbers)
+        .map(|line_number| line_number - 1)
+        .collect();
+    let mut lines: Vec<Cow<str>> = file_content.lines().map(Cow::Borrowed).collect();
+    for &index in &managed_indexes {
+        if let Some(formatted_line) = format_cargo_install_line(&lines[index]) {
+            lines[index] = Cow::Owned(formatted_line);
+        }
+    }
+    sort_git_config_lines(&mut lines, &managed_indexes);
+    align_backslashes(&mut lines, &managed_indexes);
+    let mut formatted_content = lines.join("\n");
+    if file_content.ends_with('\n') {
+        formatted_content.push('\n');
+    }
+    Ok(formatted_content)
+}
+
+fn format_cargo_install_line(line: &str) -> Option<String> {
+    let command_str = strip_command_suffix(line)?;
+    let (head, args_str) = command_str.split_once("cargo install ")?;
+    let sorted_args = sort_cargo_install_args(args_str.split(' ').filter(|arg| !arg.is_empty()))?;
+    Some(format!("{head}cargo install {}; \\", sorted_args.join(" ")))
+}
+
+/// Sort the arguments following `cargo install` in the canonical order: the crate name, the
+/// positional arguments, then the options. Return `None` without crate name.
+pub fn sort_cargo_install_args<'a>(
+    mut args: impl Iterator<Item = &'a str>,
+) -> Option<Vec<&'a str>> {
+    let crate_name = args.next()?;
+    let mut positional_args = Vec::new();
+    let mut options: Vec<(u8, Vec<&str>)> = Vec::new();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            positional_args.push(arg);
+            continue;
+        }
+        let name = arg.split_once('=').map_or(arg, |(name, _)| name);
+        let rank = CARGO_INSTALL_OPTION_RANKS
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map_or(OTHER_CARGO_INSTALL_OPTION_RANK, |(_, rank)| *rank);
+        let mut option = vec![arg];
+        if name == arg && CARGO_INSTALL_OPTIONS_WITH_VALUE.contains(&name) {
+            option.extend(args.next());
+        }
+        options.push((rank, option));
+    }
+    // The sort is stable, so options with the same rank keep their order.
+    options.sort_by_key(|(rank, _)| *rank);
+    Some(
+        itertools::chain!(
+            [crate_name],
+            positional_args,
+            options.into_iter().flat_map(|(_, option)| option)
+        )
+        .collect(),
+    )
+}
+
+/// Sort the consecutive `git config set --global` lines by option. An annotated line is not
+/// sorted, so that it keeps its annotation.
+fn sort_git_config_lines(lines: &mut [Cow<str>], managed_indexes: &[usize]) {
+    let is_git_config_line = |line: &str| line.trim_start().starts_with("git config set --global ");
+    let mut index = 0;
+    while index < lines.len() {
+        let run_len = (index..lines.len())
+            .take_while(|&line_index| {
+                managed_indexes.contains(&line_index)
+                    && is_git_config_line(&lines[line_index])
+                    && !is_annotated(lines, line_index)
+            })
+            .count();
+        lines[index..index + run_len].sort_by_key(|line| {
+            line.trim_start()
+                .trim_start_matches("git config set --global ")
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_owned()
+        });
+        index += run_len.max(1);
+    }
+}
+
+/// Return whether the line has an inline annotation or follows an annotation line.
+fn is_annotated(lines: &[Cow<str>], index: usize) -> bool {
+    let line = &lines[index];
+    let follows_annotation = index.checked_sub(1).is_some_and(|previous_index| {
+        parse_annotation_line(lines[previous_index].trim_start()).is_some()
+    });
+    follows_annotation || strip_inline_annotation(line).len() != line.len()
+}
+
+/// Align the backslashes at the end of the managed lines of each `RUN` block, with at least one
+/// space before them.
+fn align_backslashes(lines: &mut [Cow<str>], managed_indexes: &[usize]) {
+    let mut block_start = 0;
+    while block_start < lines.len() {
+        // A block ends with the first line which does not end with a backslash.
+        let block_len = lines[block_start..]
+            .iter()
+            .position(|line| !line.ends_with('\\'))
+            .map_or(lines.len() - block_start, |position| position + 1);
+        let block_indexes = block_start..block_start + block_len;
+        let aligned_indexes: Vec<_> = block_indexes
+            .filter(|index| {
+                managed_indexes.contains(index) && strip_command_suffix(&lines[*index]).is_some()
+            })
+            .collect();
+        let content_width = |line: &str| {
+            // The line ends with the command suffix so `unwrap()` is OK.
+            line.strip_suffix('\\').unwrap().trim_end().chars().count()
+        };
+        let max_content_width =
+            aligned_indexes.iter().map(|index| content_width(&lines[*index])).max();
+        for index in aligned_indexes {
+            let line = &lines[index];
+            // The line ends with a backslash so `unwrap()` is OK.
+            let content = line.strip_suffix('\\').unwrap().trim_end();
+            // There is at least one aligned line so `unwrap()` is OK.
+            let padding = max_content_width.unwrap() - content_width(line) + 1;
+            lines[index] = Cow::Owned(format!("{content}{}\\", " ".repeat(padding)));
+        }
+        block_start += block_len;
+    }
+}
@@ -2,8 +2,8 @@ use std::collections::HashMap;
 
 use anyhow::{Context as _, bail};
 
-use crate::command::{Command, command};
-use crate::common::quote;
+use crate::command::{PlannedCommand, command};
+use crate::common::{quote, strip_command_suffix};
 
 mod nonempty_str_types {
     crate::nonempty_str::newtype!(GitConfigOption, error_msg = "empty option");
@@ -14,12 +14,18 @@ pub use nonempty_str_types::{GitConfigOption, GitConfigValue};
 #[derive(Clone, Copy)]
 pub struct GitConfigSetGlobal<'a>(GitConfigOption<'a>, GitConfigValue<'a>);
 
+impl<'a> GitConfigSetGlobal<'a> {
+    pub const fn key(self) -> GitConfigOption<'a> {
+        self.0
+    }
+}
+
 pub fn parse_stripped_line_with_git_config_set_global<'a>(
     stripped_line: &'a str,
     git_map: &mut HashMap<GitConfigOption<'a>, GitConfigValue<'a>>,
 ) -> anyhow::Result<GitConfigSetGlobal<'a>> {
     let expected_suffix = "; \\";
-    let Some(option_and_value) = stripped_line.strip_suffix(expected_suffix) else {
+    let Some(option_and_value) = strip_command_suffix(stripped_line) else {
         bail!(
             "line with \"git config set --global \" but which does not end with {}",
             quote(expected_suffix)
@@ -48,22 +54,32 @@ pub fn parse_stripped_line_with_git_config_set_global<'a>(
 pub fn compute_git_global_config_removal_command<'a>(
     target_state_git_map: &HashMap<GitConfigOption<'a>, GitConfigValue<'a>>,
     current_state_action: GitConfigSetGlobal<'a>,
-) -> Option<Command<'a>> {
+) -> Option<PlannedCommand<'a>> {
     let option = &current_state_action.0;
-    (!target_state_git_map.contains_key(option))
-        .then(|| command!["git", "config", "unset", "--global", option.as_str()].unwrap())
+    (!target_state_git_map.contains_key(option)).then(|| {
+        command!["git", "config", "unset", "--global", option.as_str()]
+            .unwrap()
+            .with_reason(format!("git global option {} removed from target", option.as_str()))
+    })
 }
 
 pub fn compute_git_global_config_set_or_update_command<'a>(
     current_state_git_map: &HashMap<GitConfigOption<'a>, GitConfigValue<'a>>,
     target_state_action: GitConfigSetGlobal<'a>,
-) -> Option<Command<'a>> {
+) -> Option<PlannedCommand<'a>> {
     let GitConfigSetGlobal(option, target_state_value) = target_state_action;
-    current_state_git_map
-        .get(&option)
+    let current_state_value = current_state_git_map.get(&option);
+    current_state_value
         .is_none_or(|current_state_value| current_state_value != &target_state_value)
         .then(|| {
             let value = target_state_value.as_str();
-            command!["git", "config", "set", "--global", option.as_str(), value].unwrap()
+            let reason = if let Some(current_state_value) = current_state_value {
+                format!("{}={} -> {value}", option.as_str(), current_state_value.as_str())
+            } else {
+                format!("git global option {} added to target", option.as_str())
+            };
+            command!["git", "config", "set", "--global", option.as_str(), value]
+                .unwrap()
+                .with_reason(reason)
         })
 }
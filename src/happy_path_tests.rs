@@ -1,7 +1,18 @@
+use std::borrow::Cow;
+use std::cell::OnceCell;
+
 use anyhow::Context as _;
 
-use crate::command::Command;
-use crate::command_computing::{compute_commands, parse_state_from_file_content};
+use crate::annotation_handling::{AnnotationContext, Profile};
+use crate::command::Lock;
+use crate::command_computing::{
+    compute_base_image_change, compute_commands, parse_state_from_file_content,
+};
+use crate::formatting::format_file_content;
+use crate::stage_handling::BaseImage;
+use crate::variable_expansion::{
+    EnvScope, child_environment, env_declarations, expand_variables, to_shell_word,
+};
 
 const FILE_CONTENT_1: &str = include_str!("../dockerfiles/tested_example_1");
 const FILE_CONTENT_2: &str = include_str!("../dockerfiles/tested_example_2");
@@ -57,6 +68,67 @@ fn remove() {
     );
 }
 
+#[test]
+fn dependency_ordering() {
+    let file_content = r"RUN set -eux; \
+        pixi global install git=2.55.0; \
+        git config set --global user.name 'John Smith'; \
+        true
+    RUN set -eux; \
+        pixi run -e make cargo install fd-find --version 10.4.2 --locked; \
+        cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag v0.73.0 --locked; \
+        true";
+    assert_eq!(
+        parse_args_and_compute_commands("", file_content).unwrap(),
+        split_commands([
+            "cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag v0.73.0 --locked",
+            "pixi global install git=2.55.0",
+            "git config set --global user.name 'John Smith'",
+            "pixi run -e make cargo install fd-find --version 10.4.2 --locked",
+        ]),
+    );
+    assert_eq!(
+        parse_args_and_compute_commands(file_content, "").unwrap(),
+        split_commands([
+            "cargo uninstall fd-find",
+            "git config unset --global user.name",
+            "pixi global uninstall git",
+            "cargo uninstall pixi",
+        ]),
+    );
+}
+
+#[test]
+fn reasons() {
+    let current_state = parse_state_from_file_content(FILE_CONTENT_1, None, None).unwrap();
+    let target_state = parse_state_from_file_content(FILE_CONTENT_2, None, None).unwrap();
+    assert_eq!(
+        compute_commands(&current_state, &target_state, false)
+            .map(|planned_command| planned_command.reason)
+            .collect::<Vec<_>>(),
+        [
+            "crate cargo-cache: [cargo install cargo-cache --version 0.8.3 --locked] -> \
+            [cargo install cargo-cache --version 0.8.3]",
+            "git=2.51.2 -> 2.55.0",
+            "init.defaultBranch=master -> main",
+        ],
+    );
+    let empty_state = parse_state_from_file_content("", None, None).unwrap();
+    assert_eq!(
+        compute_commands(&target_state, &empty_state, false)
+            .map(|planned_command| planned_command.reason)
+            .collect::<Vec<_>>(),
+        [
+            "git global option user.name removed from target",
+            "git global option init.defaultBranch removed from target",
+            "recipe git removed from target",
+            "crate fd-find removed from target",
+            "crate pixi removed from target",
+            "crate cargo-cache removed from target",
+        ],
+    );
+}
+
 #[test]
 fn no_change() {
     assert_eq!(
@@ -69,15 +141,719 @@ fn no_change() {
     );
 }
 
+#[test]
+fn equivalent_git_urls() {
+    let current_state_file_content = r"RUN set -eux; \
+        cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag v0.73.0 --locked; \
+        cargo install fsays --git=git@GitHub.com:DenisNavarro/fsays --tag v0.3.0; \
+        cargo install bar --git https://example.com/bar --locked; \
+        echo done";
+    let target_state_file_content = r"RUN set -eux; \
+        cargo install pixi --git https://github.com/prefix-dev/pixi/ --tag v0.73.0 --locked; \
+        cargo install fsays --git=https://github.com/DenisNavarro/fsays.git --tag v0.3.0; \
+        cargo install bar --git https://example.com/baz --locked; \
+        echo done";
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        split_commands(["cargo install bar --git https://example.com/baz --locked --force"]),
+    );
+}
+
+#[test]
+fn formatting_plans_no_command() {
+    let current_state_file_content = r"RUN set -eux; \
+        cargo install pixi --locked --tag v0.73.0 --git https://github.com/prefix-dev/pixi.git; \
+        cargo install fsays --force --features color --version 0.3.0; \
+        true";
+    let target_state_file_content = format_file_content(current_state_file_content).unwrap();
+    assert_ne!(target_state_file_content, current_state_file_content);
+    let current_state =
+        parse_state_from_file_content(current_state_file_content, None, None).unwrap();
+    let target_state =
+        parse_state_from_file_content(&target_state_file_content, None, None).unwrap();
+    assert_eq!(compute_commands(&current_state, &target_state, false).count(), 0);
+}
+
+#[test]
+fn formatting_keeps_the_annotations_on_their_lines() {
+    let file_content = r"RUN set -eux; \
+    # sync_install: only=container
+    git config set --global user.name 'John Smith'; \
+    git config set --global init.defaultBranch main; \
+    git config set --global core.editor vim; \
+    true";
+    assert_eq!(
+        format_file_content(file_content).unwrap(),
+        r"RUN set -eux; \
+    # sync_install: only=container
+    git config set --global user.name 'John Smith';  \
+    git config set --global core.editor vim;         \
+    git config set --global init.defaultBranch main; \
+    true"
+    );
+}
+
+#[test]
+fn variable_expansion() {
+    let file_content = r#"ARG PIXI_VERSION=v0.73.0 FSAYS_VERSION=0.2.0
+ENV HOME="/root" GIT_VERSION=2.55.0
+ENV PATH="$HOME/.pixi/bin:$PATH" PIXI_TAG=$PIXI_VERSION
+RUN set -eux; \
+    cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag ${PIXI_VERSION} --locked; \
+    cargo install fsays --version $FSAYS_VERSION --locked; \
+    pixi global install git=$GIT_VERSION; \
+    echo '$HOME' "$HOME" $UNKNOWN \$HOME
+COPY <<EOF /root/.config/x
+$HOME
+EOF
+# $HOME
+"#;
+    let build_args = [("FSAYS_VERSION".to_owned(), "0.3.0".to_owned())];
+    let expansion = expand_variables(file_content, &build_args);
+    assert_eq!(
+        expansion.file_content,
+        r#"ARG PIXI_VERSION=v0.73.0 FSAYS_VERSION=0.2.0
+ENV HOME="/root" GIT_VERSION=2.55.0
+ENV PATH="$HOME/.pixi/bin:$PATH" PIXI_TAG=$PIXI_VERSION
+RUN set -eux; \
+    cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag v0.73.0 --locked; \
+    cargo install fsays --version 0.3.0 --locked; \
+    pixi global install git=2.55.0; \
+    echo '$HOME' "/root" $UNKNOWN \$HOME
+COPY <<EOF /root/.config/x
+$HOME
+EOF
+# $HOME
+"#
+    );
+    let env_variables = &expansion.env_variables;
+    let path = std::env::var("PATH").unwrap();
+    let home = std::env::var("HOME").unwrap();
+    let declarations = env_declarations(&[EnvScope { env_variables, line_number: Some(3) }], false);
+    assert_eq!(
+        declarations,
+        [
+            ("HOME".to_owned(), "\"/root\"".to_owned()),
+            ("GIT_VERSION".to_owned(), "2.55.0".to_owned())
+        ]
+    );
+    assert_eq!(
+        child_environment(&declarations),
+        [("HOME".to_owned(), "/root".to_owned()), ("GIT_VERSION".to_owned(), "2.55.0".to_owned())]
+    );
+    let declarations = env_declarations(&[EnvScope { env_variables, line_number: Some(5) }], true);
+    assert_eq!(
+        declarations,
+        [
+            ("GIT_VERSION".to_owned(), "2.55.0".to_owned()),
+            ("PATH".to_owned(), "\"$HOME/.pixi/bin:$PATH\"".to_owned()),
+            ("PIXI_TAG".to_owned(), "v0.73.0".to_owned()),
+        ]
+    );
+    assert_eq!(
+        child_environment(&declarations),
+        [
+            ("GIT_VERSION".to_owned(), "2.55.0".to_owned()),
+            ("PATH".to_owned(), format!("{home}/.pixi/bin:{path}")),
+            ("PIXI_TAG".to_owned(), "v0.73.0".to_owned()),
+        ]
+    );
+    assert_eq!(
+        to_shell_word(r#""$HOME/.pixi/bin:${PATH}"'$HOME'`"#),
+        r#""${HOME-\$HOME}/.pixi/bin:${PATH-\${PATH\}}\$HOME\`""#
+    );
+}
+
+#[test]
+fn arg_scope_of_the_stages() {
+    let file_content = r"ARG RUST_VERSION=1.97.1 FSAYS_VERSION=0.2.0
+FROM docker.io/library/rust:${RUST_VERSION}-slim-bookworm AS builder
+ARG FSAYS_VERSION
+ARG CACHE_VERSION=0.8.3
+RUN cargo install fsays --version $FSAYS_VERSION --locked; \
+    cargo install cargo-cache --version $CACHE_VERSION --locked
+FROM docker.io/library/debian:bookworm-slim AS runtime
+RUN echo $RUST_VERSION $FSAYS_VERSION $CACHE_VERSION";
+    let build_args = [("FSAYS_VERSION".to_owned(), "0.3.0".to_owned())];
+    assert_eq!(
+        expand_variables(file_content, &build_args).file_content,
+        r"ARG RUST_VERSION=1.97.1 FSAYS_VERSION=0.2.0
+FROM docker.io/library/rust:1.97.1-slim-bookworm AS builder
+ARG FSAYS_VERSION
+ARG CACHE_VERSION=0.8.3
+RUN cargo install fsays --version 0.3.0 --locked; \
+    cargo install cargo-cache --version 0.8.3 --locked
+FROM docker.io/library/debian:bookworm-slim AS runtime
+RUN echo $RUST_VERSION $FSAYS_VERSION $CACHE_VERSION"
+    );
+    assert_eq!(
+        expand_variables(file_content, &[]).file_content.lines().nth(4),
+        Some(r"RUN cargo install fsays --version 0.2.0 --locked; \")
+    );
+}
+
+#[test]
+fn stages() {
+    let file_content = r"FROM docker.io/library/rust:1.97.1-slim-bookworm AS builder
+RUN set -eux; \
+    cargo install fsays --version 0.3.0 --locked; \
+    true
+FROM docker.io/library/debian:bookworm-slim AS runtime
+RUN set -eux; \
+    cargo install fsays --version 0.2.0 --locked; \
+    pixi global install git=2.55.0; \
+    true";
+    let empty_state = parse_state_from_file_content("", Some("builder"), None).unwrap();
+    let compute_stage_commands = |stage_name| {
+        let state = parse_state_from_file_content(file_content, stage_name, None).unwrap();
+        compute_commands(&empty_state, &state, false)
+            .map(|planned_command| planned_command.command.into_vec())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        compute_stage_commands(None),
+        split_commands([
+            "cargo install fsays --version 0.2.0 --locked",
+            "pixi global install git=2.55.0",
+        ]),
+    );
+    assert_eq!(
+        compute_stage_commands(Some("Builder")),
+        split_commands(["cargo install fsays --version 0.3.0 --locked"]),
+    );
+}
+
+#[test]
+fn base_image_change() {
+    let current_state_file_content = r"FROM docker.io/library/rust:1.97.1-slim-bookworm
+RUN set -eux; \
+    cargo install cargo-cache --version 0.8.3 --locked; \
+    cargo install fsays --version 0.2.0 --locked; \
+    pixi global install git=2.55.0; \
+    true";
+    let target_state_file_content = r"FROM docker.io/library/rust:1.98.0-slim-bookworm
+RUN set -eux; \
+    cargo install cargo-cache --version 0.8.3 --locked; \
+    cargo install fsays --version 0.3.0 --locked; \
+    pixi global install git=2.55.0; \
+    true";
+    let current_state =
+        parse_state_from_file_content(current_state_file_content, None, None).unwrap();
+    let target_state =
+        parse_state_from_file_content(target_state_file_content, None, None).unwrap();
+    assert_eq!(
+        compute_base_image_change(&current_state, &target_state)
+            .map(|base_images| base_images.map(BaseImage::as_str)),
+        Some([
+            "docker.io/library/rust:1.97.1-slim-bookworm",
+            "docker.io/library/rust:1.98.0-slim-bookworm"
+        ]),
+    );
+    assert!(compute_base_image_change(&target_state, &target_state).is_none());
+    for (rebuild_on_base_change, expected_reasons) in [
+        (
+            false,
+            ["crate fsays: [cargo install fsays --version 0.2.0 --locked] -> \
+            [cargo install fsays --version 0.3.0 --locked]"]
+            .as_slice(),
+        ),
+        (
+            true,
+            [
+                "crate cargo-cache rebuilt: Rust 1.97.1 -> 1.98.0",
+                "crate fsays: [cargo install fsays --version 0.2.0 --locked] -> \
+                [cargo install fsays --version 0.3.0 --locked]",
+            ]
+            .as_slice(),
+        ),
+    ] {
+        assert_eq!(
+            compute_commands(&current_state, &target_state, rebuild_on_base_change)
+                .map(|planned_command| planned_command.reason)
+                .collect::<Vec<_>>(),
+            expected_reasons,
+        );
+    }
+    for (base_image, rust_version) in [
+        ("docker.io/library/rust:1.98.0-slim-bookworm", Some("1.98.0")),
+        ("rust:1.98@sha256:0123", Some("1.98")),
+        ("rust:latest", None),
+        ("localhost:5000/rust", None),
+        ("docker.io/library/debian:12.8", None),
+    ] {
+        assert_eq!(BaseImage::from_str(base_image).unwrap().rust_version(), rust_version);
+    }
+}
+
+#[test]
+fn rust_version_of_a_builder_stage() {
+    let file_content = |rust_version| {
+        format!(
+            "FROM docker.io/library/rust:{rust_version}-slim-bookworm AS builder\n\
+            FROM docker.io/library/debian:bookworm-slim AS runtime\n\
+            RUN set -eux; \\\n\
+            \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+            \x20   true\n"
+        )
+    };
+    let current_state_file_content = file_content("1.97.1");
+    let target_state_file_content = file_content("1.98.0");
+    let current_state =
+        parse_state_from_file_content(&current_state_file_content, None, None).unwrap();
+    let target_state =
+        parse_state_from_file_content(&target_state_file_content, None, None).unwrap();
+    // The runtime stage is compared, but the crates are built by the Rust of the builder stage.
+    assert!(compute_base_image_change(&current_state, &target_state).is_none());
+    assert_eq!(
+        compute_commands(&current_state, &target_state, true)
+            .map(|planned_command| planned_command.reason)
+            .collect::<Vec<_>>(),
+        ["crate fsays rebuilt: Rust 1.97.1 -> 1.98.0"],
+    );
+}
+
+#[test]
+fn run_heredocs() {
+    let current_state_file_content = r"RUN set -eux; \
+    cargo install fsays --version 0.3.0 --locked; \
+    pixi global install git=2.55.0; \
+    git config set --global init.defaultBranch main; \
+    git config set --global user.name 'John Smith'; \
+    true";
+    let target_state_file_content = "RUN <<EOF
+#!/bin/sh
+set -eux
+cargo install fsays --version 0.3.0 --locked
+# pixi is installed by cargo
+pixi global install git=2.55.0;
+EOF
+RUN --mount=type=cache,target=/root/.cache --network=none <<-'END'
+\tgit config set --global init.defaultBranch main
+\t\tgit config set --global user.name 'John Smith'; \\
+\ttrue
+\tEND
+";
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        Vec::<Vec<&'static str>>::new()
+    );
+    assert_eq!(
+        parse_args_and_compute_commands("", target_state_file_content).unwrap(),
+        split_commands([
+            "cargo install fsays --version 0.3.0 --locked",
+            "pixi global install git=2.55.0",
+            "git config set --global init.defaultBranch main",
+            "git config set --global user.name 'John Smith'",
+        ]),
+    );
+}
+
+#[test]
+fn heredocs_which_are_not_scripts() {
+    let file_content = "RUN cat <<EOF > /etc/foo
+cargo install fsays --version 0.3.0 --locked
+EOF
+RUN python3 <<'EOF'
+# sync_install: not an annotation
+print('pixi global install git=2.55.0')
+EOF
+RUN cat <<EOF>/etc/bar
+ln -s /tmp /root/tmp
+EOF
+RUN set -eux; \\
+    cargo install cargo-cache --version 0.8.3 --locked; \\
+    true
+";
+    assert_eq!(
+        parse_args_and_compute_commands("", file_content).unwrap(),
+        split_commands(["cargo install cargo-cache --version 0.8.3 --locked"]),
+    );
+}
+
+#[test]
+fn annotations() {
+    let file_content = "RUN set -eux; \\
+    # sync_install: ignore
+    cargo install cargo-cache --version 0.8.3 --locked; \\
+    # sync_install: only=container
+    cargo install fsays --version 0.3.0 --locked; \\
+    # sync_install: only=host
+    cargo install fsays --version 0.2.0 --locked; \\
+    true
+RUN <<EOF
+pixi global install git=2.55.0 # sync_install: only=container
+git config set --global init.defaultBranch main # sync_install: ignore
+EOF
+";
+    let empty_state = parse_state_from_file_content("", None, None).unwrap();
+    let compute_profile_commands = |profile| {
+        let hostname = OnceCell::from("laptop".to_owned());
+        let context = AnnotationContext { profile, arch: "x86_64", hostname };
+        let state = parse_state_from_file_content(file_content, None, Some(&context)).unwrap();
+        compute_commands(&empty_state, &state, false)
+            .map(|planned_command| planned_command.command.into_vec())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        compute_profile_commands(Profile::Host),
+        split_commands(["cargo install fsays --version 0.2.0 --locked"]),
+    );
+    assert_eq!(
+        compute_profile_commands(Profile::Container),
+        split_commands([
+            "cargo install fsays --version 0.3.0 --locked",
+            "pixi global install git=2.55.0",
+        ]),
+    );
+}
+
+#[test]
+fn host_conditions() {
+    let file_content = "RUN set -eux; \\
+    # sync_install: when arch=x86_64
+    cargo install fsays --version 0.3.0 --locked; \\
+    # sync_install: when arch=aarch64
+    cargo install fsays --version 0.2.0 --locked; \\
+    # sync_install: when hostname=~dev-*
+    # sync_install: only=host
+    snap install vlc; \\
+    true
+RUN <<EOF
+pixi global install git=2.55.0 # sync_install: when hostname=~*-0?
+EOF
+";
+    let empty_state = parse_state_from_file_content("", None, None).unwrap();
+    let compute_host_commands = |profile, hostname: &str| {
+        let hostname = OnceCell::from(hostname.to_owned());
+        let context = AnnotationContext { profile, arch: "x86_64", hostname };
+        let state = parse_state_from_file_content(file_content, None, Some(&context)).unwrap();
+        compute_commands(&empty_state, &state, false)
+            .map(|planned_command| planned_command.command.into_vec())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        compute_host_commands(Profile::Host, "dev-01"),
+        split_commands([
+            "cargo install fsays --version 0.3.0 --locked",
+            "sudo snap install vlc",
+            "pixi global install git=2.55.0",
+        ]),
+    );
+    assert_eq!(
+        compute_host_commands(Profile::Container, "dev-01"),
+        split_commands([
+            "cargo install fsays --version 0.3.0 --locked",
+            "pixi global install git=2.55.0",
+        ]),
+    );
+    assert_eq!(
+        compute_host_commands(Profile::Host, "build-10"),
+        split_commands(["cargo install fsays --version 0.3.0 --locked"]),
+    );
+}
+
+#[test]
+fn download() {
+    let current_state_file_content = r#"RUN set -eux; \
+        curl -fsSL file:///tmp/gitalias.txt -o /root/.gitalias; \
+        wget file:///tmp/unchanged.txt -O /root/unchanged.txt; \
+        wget file:///tmp/removed.txt -O /root/removed.txt; \
+        echo "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb  /root/removed.txt" | sha256sum -c; \
+        true"#;
+    let target_state_file_content = r#"RUN set -eux; \
+        curl -fsSL file:///tmp/gitalias.txt -o /root/.gitalias; \
+        echo "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa  /root/.gitalias" | sha256sum -c; \
+        curl -fsSL file:///tmp/unchanged.txt -o /root/unchanged.txt; \
+        curl -fsSL file:///tmp/added.txt -o /root/added.txt; \
+        true"#;
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        split_commands([
+            "rm -f /root/removed.txt",
+            "curl -fsSL file:///tmp/gitalias.txt -o /root/.gitalias",
+            r#"sh -c 'echo "$1  $2" | sha256sum -c' sh aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa /root/.gitalias"#,
+            "curl -fsSL file:///tmp/added.txt -o /root/added.txt",
+        ]),
+    );
+}
+
+#[test]
+fn download_to_the_home_directory_and_unmanaged_download() {
+    let target_state_file_content = r#"RUN set -eux; \
+        curl -fsSL https://sh.rustup.rs | sh -s -- -y; \
+        wget file:///tmp/gitalias.txt -O ~/.gitalias; \
+        echo "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa  ~/.gitalias" | sha256sum -c; \
+        true"#;
+    let empty_state = parse_state_from_file_content("", None, None).unwrap();
+    let state = parse_state_from_file_content(target_state_file_content, None, None).unwrap();
+    let commands: Vec<_> = compute_commands(&empty_state, &state, false)
+        .map(|planned_command| planned_command.command)
+        .collect();
+    let sha256 = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    assert_eq!(
+        commands.iter().map(|command| format!("{}", command.display())).collect::<Vec<_>>(),
+        [
+            "wget file:///tmp/gitalias.txt -O ~/.gitalias".to_owned(),
+            format!("sh -c 'echo \"$1  $2\" | sha256sum -c' sh {sha256} ~/.gitalias"),
+        ],
+    );
+    assert_eq!(
+        commands
+            .iter()
+            .map(|command| command.process_args(Some("/home/alice/")).collect())
+            .collect::<Vec<Vec<_>>>(),
+        [
+            ["file:///tmp/gitalias.txt", "-O", "/home/alice/.gitalias"].map(str::to_owned).to_vec(),
+            ["-c", "echo \"$1  $2\" | sha256sum -c", "sh", sha256, "/home/alice/.gitalias"]
+                .map(str::to_owned)
+                .to_vec(),
+        ],
+    );
+}
+
+const APT_FILE_CONTENT: &str = r#"COPY <<EOF /etc/apt/sources.list.d/vscodium.sources
+Types: deb
+URIs: https://download.vscodium.com/debs
+Suites: vscodium
+Components: main
+Signed-By: /usr/share/keyrings/vscodium-archive-keyring.asc
+EOF
+
+COPY <<"EOF" /work/not_an_apt_file.txt
+cargo install fsays --version 0.3.0 --locked; \
+EOF
+
+RUN set -eux; \
+    printf 'Types: deb\nURIs: https://repo.vivaldi.com/archive/deb/\nSuites: stable\nComponents: main\n' > /etc/apt/sources.list.d/vivaldi.sources; \
+    apt-get update"#;
+
+#[test]
+fn apt_files() {
+    let current_state_file_content = r"RUN set -eux; \
+        printf 'Types: deb\nURIs: https://download.vscodium.com/debs\nSuites: vscodium\n' > /etc/apt/sources.list.d/vscodium.sources; \
+        printf 'Types: deb\n' > /etc/apt/sources.list.d/removed.sources; \
+        apt-get update";
+    let target_state_file_content = APT_FILE_CONTENT;
+    let write_script = r#"printf '%s' "$1" > "$2""#;
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        vec![
+            vec!["sudo", "rm", "-f", "/etc/apt/sources.list.d/removed.sources"],
+            vec![
+                "sudo",
+                "sh",
+                "-c",
+                write_script,
+                "sh",
+                "Types: deb\nURIs: https://download.vscodium.com/debs\nSuites: vscodium\n\
+                Components: main\nSigned-By: /usr/share/keyrings/vscodium-archive-keyring.asc\n",
+                "/etc/apt/sources.list.d/vscodium.sources",
+            ],
+            vec![
+                "sudo",
+                "sh",
+                "-c",
+                write_script,
+                "sh",
+                "Types: deb\nURIs: https://repo.vivaldi.com/archive/deb/\nSuites: stable\n\
+                Components: main\n",
+                "/etc/apt/sources.list.d/vivaldi.sources",
+            ],
+            vec!["sudo", "apt-get", "update"],
+        ],
+    );
+    assert_eq!(
+        parse_args_and_compute_commands(target_state_file_content, target_state_file_content)
+            .unwrap(),
+        Vec::<Vec<&'static str>>::new()
+    );
+}
+
+#[test]
+fn keyring_download() {
+    let state_file_content = |url| {
+        format!(
+            "RUN set -eux; \\\n\
+            \x20   curl -fsSL {url} -o /usr/share/keyrings/vscodium-archive-keyring.asc; \\\n\
+            \x20   curl -fsSL https://example.com/notes.txt -o /root/notes.txt; \\\n\
+            \x20   apt-get update\n"
+        )
+    };
+    let current_state_file_content = state_file_content("https://example.com/old.asc");
+    let target_state_file_content = state_file_content("https://example.com/new.asc");
+    let current_state =
+        parse_state_from_file_content(&current_state_file_content, None, None).unwrap();
+    let target_state =
+        parse_state_from_file_content(&target_state_file_content, None, None).unwrap();
+    let planned_commands: Vec<_> = compute_commands(&current_state, &target_state, false)
+        .map(|planned_command| {
+            let is_apt_locked = planned_command.lock == Some(Lock::Apt);
+            (format!("{}", planned_command.command.display()), is_apt_locked)
+        })
+        .collect();
+    assert_eq!(
+        planned_commands,
+        [
+            (
+                "curl -fsSL https://example.com/new.asc -o \
+                /usr/share/keyrings/vscodium-archive-keyring.asc"
+                    .to_owned(),
+                true
+            ),
+            ("sudo apt-get update".to_owned(), true),
+        ]
+    );
+    // A changed download which is not an APT file is not followed by `apt-get update`.
+    let notes_state_file_content =
+        target_state_file_content.replace("notes.txt -o", "other_notes.txt -o");
+    let notes_state = parse_state_from_file_content(&notes_state_file_content, None, None).unwrap();
+    let planned_commands: Vec<_> = compute_commands(&target_state, &notes_state, false)
+        .map(|planned_command| planned_command.lock == Some(Lock::Downloads))
+        .collect();
+    assert_eq!(planned_commands, [true]);
+}
+
+#[test]
+fn heredoc_with_leading_tabs_and_legacy_apt_source() {
+    let target_state_file_content = "COPY <<-EOF /etc/motd\n\
+        \tcargo install fsays --version 0.3.0 --locked; \\\n\
+        \tEOF\n\
+        COPY <<-\"EOF\" /etc/apt/sources.list.d/vscodium.sources\n\
+        \tTypes: deb\n\
+        \tEOF\n\
+        RUN set -eux; \\\n\
+        \x20   printf 'deb https://repo.vivaldi.com/archive/deb/ stable main\\n' > \
+        /etc/apt/sources.list.d/vivaldi.list; \\\n\
+        \x20   apt-get update\n";
+    assert_eq!(
+        parse_args_and_compute_commands("", target_state_file_content).unwrap(),
+        vec![
+            vec![
+                "sudo",
+                "sh",
+                "-c",
+                r#"printf '%s' "$1" > "$2""#,
+                "sh",
+                "Types: deb\n",
+                "/etc/apt/sources.list.d/vscodium.sources",
+            ],
+            vec!["sudo", "apt-get", "update"],
+        ],
+    );
+}
+
+#[test]
+fn apt_files_rendering() {
+    let state = parse_state_from_file_content(APT_FILE_CONTENT, None, None).unwrap();
+    let root_dir_path = std::env::temp_dir()
+        .join(format!("sync_install_apt_files_rendering_{}", std::process::id()));
+    state.render_apt_files(&root_dir_path).unwrap();
+    let read = |path: &str| std::fs::read_to_string(root_dir_path.join(path)).unwrap();
+    assert_eq!(
+        read("etc/apt/sources.list.d/vivaldi.sources"),
+        "Types: deb\nURIs: https://repo.vivaldi.com/archive/deb/\nSuites: stable\nComponents: main\n"
+    );
+    assert!(read("etc/apt/sources.list.d/vscodium.sources").ends_with("keyring.asc\n"));
+    assert!(!root_dir_path.join("work").exists());
+    std::fs::remove_dir_all(&root_dir_path).unwrap();
+}
+
+#[test]
+fn snap() {
+    let current_state_file_content = r"RUN set -eux; \
+        snap install vlc; \
+        snap install --classic codium; \
+        snap install --channel=edge gimp; \
+        snap install removed; \
+        true";
+    let target_state_file_content = r"RUN set -eux; \
+        snap install vlc; \
+        snap install --classic --channel=1.99/stable codium; \
+        snap install gimp; \
+        snap install --classic added; \
+        true";
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        split_commands([
+            "sudo snap remove removed",
+            "sudo snap refresh --channel=1.99/stable --classic codium",
+            "sudo snap refresh --channel=latest/stable gimp",
+            "sudo snap install --classic added",
+        ]),
+    );
+}
+
+#[test]
+fn flatpak() {
+    let current_state_file_content = r"RUN set -eux; \
+        flatpak install -y flathub org.gimp.GIMP; \
+        flatpak install -y --user flathub org.videolan.VLC; \
+        flatpak install -y flathub org.inkscape.Inkscape; \
+        flatpak update -y --commit=0123abcd org.inkscape.Inkscape; \
+        flatpak install -y flathub org.removed.Removed; \
+        true";
+    let target_state_file_content = r"RUN set -eux; \
+        flatpak install -y flathub org.gimp.GIMP; \
+        flatpak update -y --commit=4567cdef org.gimp.GIMP; \
+        flatpak install -y flathub org.videolan.VLC; \
+        flatpak install -y flathub org.inkscape.Inkscape; \
+        flatpak install -y --user flathub org.added.Added; \
+        true";
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        split_commands([
+            "flatpak uninstall -y --system org.removed.Removed",
+            "flatpak uninstall -y --user org.videolan.VLC",
+            "flatpak update -y --system --commit=4567cdef org.gimp.GIMP",
+            "flatpak install -y --system flathub org.videolan.VLC",
+            "flatpak update -y --system org.inkscape.Inkscape",
+            "flatpak install -y --user flathub org.added.Added",
+        ]),
+    );
+}
+
+#[test]
+fn symlink() {
+    let current_state_file_content = r"RUN set -eux; \
+        ln -sfn /work/dotfiles/.bashrc /root/.bashrc; \
+        ln -s /work/dotfiles/.vimrc /root/.vimrc; \
+        ln -sf /work/dotfiles/.removed /root/.removed; \
+        true";
+    let target_state_file_content = r"RUN set -eux; \
+        ln -s /work/dotfiles/.bashrc /root/.bashrc; \
+        ln -sfn /work/other_dotfiles/.vimrc /root/.vimrc; \
+        ln -sfn /work/dotfiles/.inputrc /root/.inputrc; \
+        ln /work/dotfiles/.profile /root/.profile; \
+        ln -sn /work/dotfiles/.config /root/.config; \
+        true";
+    assert_eq!(
+        parse_args_and_compute_commands(current_state_file_content, target_state_file_content)
+            .unwrap(),
+        split_commands([
+            "rm /root/.removed",
+            "ln -sfn /work/other_dotfiles/.vimrc /root/.vimrc",
+            "ln -sfn /work/dotfiles/.inputrc /root/.inputrc",
+        ]),
+    );
+}
+
 fn parse_args_and_compute_commands(
     current_state_file_content: &'static str,
     target_state_file_content: &'static str,
-) -> anyhow::Result<Vec<Vec<&'static str>>> {
-    let current_state = parse_state_from_file_content(current_state_file_content)
+) -> anyhow::Result<Vec<Vec<Cow<'static, str>>>> {
+    let current_state = parse_state_from_file_content(current_state_file_content, None, None)
         .context("failed to parse the current state file content")?;
-    let target_state = parse_state_from_file_content(target_state_file_content)
+    let target_state = parse_state_from_file_content(target_state_file_content, None, None)
         .context("failed to parse the target state file content")?;
-    Ok(compute_commands(&current_state, &target_state).map(Command::into_vec).collect())
+    Ok(compute_commands(&current_state, &target_state, false)
+        .map(|planned_command| planned_command.command.into_vec())
+        .collect())
 }
 
 fn split_commands<const N: usize>(commands: [&'static str; N]) -> Vec<Vec<String>> {
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, anyhow};
+use itertools::Itertools as _;
+
+use crate::annotation_handling::AnnotationContext;
+use crate::command_computing::{
+    State, include_in_stages, parse_stages_from_file_content, select_stage,
+};
+use crate::common::quote_path;
+use crate::variable_expansion::{Expansion, expand_variables};
+
+const INCLUDE_DIRECTIVE_PREFIX: &str = "# sync_install: include ";
+
+/// File read because of a `# sync_install: include PATH` line, with the files it includes
+pub struct IncludedFile {
+    pub path: PathBuf,
+    /// Number of the include directive in the including file
+    pub line_number: usize,
+    pub expansion: Expansion,
+    pub included_files: Vec<Self>,
+}
+
+/// Return the path of `# sync_install: include PATH`.
+fn parse_include_directive(left_trimmed_line: &str) -> Option<&str> {
+    let path_str = left_trimmed_line.strip_prefix(INCLUDE_DIRECTIVE_PREFIX)?.trim();
+    (!path_str.is_empty()).then_some(path_str)
+}
+
+/// Read the files included by the given file, recursively. A relative path is relative to the
+/// directory of the including file.
+///
+/// The outer error is a failure to read a file, the inner one is an include cycle.
+pub fn read_included_files(
+    file_path: &Path,
+    file_content: &str,
+    build_args: &[(String, String)],
+) -> anyhow::Result<anyhow::Result<Vec<IncludedFile>>> {
+    // A path given on the command line, like `/dev/fd/63`, may not be canonicalizable.
+    let canonical_path = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_owned());
+    read_included_files_with_ancestors(
+        file_path,
+        file_content,
+        build_args,
+        &mut vec![canonical_path],
+    )
+}
+
+fn read_included_files_with_ancestors(
+    file_path: &Path,
+    file_content: &str,
+    build_args: &[(String, String)],
+    ancestors: &mut Vec<PathBuf>,
+) -> anyhow::Result<anyhow::Result<Vec<IncludedFile>>> {
+    let directory = file_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut included_files = Vec::new();
+    for (line_number, line) in (1..).zip(file_content.lines()) {
+        let Some(path_str) = parse_include_directive(line.trim_start()) else {
+            continue;
+        };
+        let path = directory.join(path_str);
+        let canonical_path = fs::canonicalize(&path).with_context(|| {
+            format!("failed to read {} included at line {line_number}", quote_path(&path))
+        })?;
+        if let Some(index) = ancestors.iter().position(|ancestor| *ancestor == canonical_path) {
+            return Ok(Err(anyhow!(
+                "include cycle: {} -> {}",
+                ancestors[index..].iter().map(|ancestor| quote_path(ancestor)).join(" -> "),
+                quote_path(&canonical_path)
+            )));
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", quote_path(&path)))?;
+        let expansion = expand_variables(&content, build_args);
+        ancestors.push(canonical_path);
+        let nested_result = read_included_files_with_ancestors(
+            &path,
+            &expansion.file_content,
+            build_args,
+            ancestors,
+        )?;
+        let Ok(nested_included_files) = nested_result else {
+            return Ok(nested_result);
+        };
+        ancestors.pop();
+        included_files.push(IncludedFile {
+            path,
+            line_number,
+            expansion,
+            included_files: nested_included_files,
+        });
+    }
+    Ok(Ok(included_files))
+}
+
+/// Return the chain of included files from one of the given files to the file with the given path,
+/// or `None` if no included file has this path.
+pub fn find_include_chain<'a>(
+    included_files: &'a [IncludedFile],
+    file_path: &Path,
+) -> Option<Vec<&'a IncludedFile>> {
+    included_files.iter().find_map(|included_file| {
+        if included_file.path == file_path {
+            return Some(vec![included_file]);
+        }
+        let mut include_chain = find_include_chain(&included_file.included_files, file_path)?;
+        include_chain.insert(0, included_file);
+        Some(include_chain)
+    })
+}
+
+/// Parse the file and the files it includes, then select a stage. The actions of an included file
+/// are merged at the line of its include directive, in the stage of this line. The included files
+/// use their last stage.
+pub fn parse_state_with_includes<'a>(
+    file_path: &'a Path,
+    file_content: &'a str,
+    included_files: &'a [IncludedFile],
+    stage_name: Option<&str>,
+    context: &AnnotationContext,
+) -> anyhow::Result<State<'a>> {
+    let mut stages = parse_stages_from_file_content(file_content, Some(context))?;
+    for included_file in included_files {
+        let included_state = parse_state_with_includes(
+            &included_file.path,
+            &included_file.expansion.file_content,
+            &included_file.included_files,
+            None,
+            context,
+        )
+        .with_context(|| {
+            format!("failed to parse the content of {}", quote_path(&included_file.path))
+        })?;
+        include_in_stages(
+            &mut stages,
+            file_path,
+            included_file.line_number,
+            &included_file.path,
+            included_state,
+        )?;
+    }
+    select_stage(stages, stage_name)
+}
@@ -0,0 +1,144 @@
+use crate::command::SourceLine;
+use crate::command_computing::parse_stages_and_line_errors_from_file_content;
+use crate::common::{parse_heredoc_delimiter, quote, strip_command_suffix, strip_heredoc_tabs};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// `sync_install` refuses the file.
+    Error,
+    /// `sync_install` accepts the file, but the target state is probably not reproducible.
+    Warning,
+}
+
+impl Severity {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+/// Problem at a line and a column, both starting from 1
+pub struct Diagnostic {
+    pub line_number: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Return all the problems of the file, sorted by position.
+pub fn lint_file_content(file_content: &str) -> Vec<Diagnostic> {
+    let (_, line_errors) = parse_stages_and_line_errors_from_file_content(file_content, None);
+    let mut diagnostics: Vec<_> = line_errors
+        .iter()
+        .map(|line_error| Diagnostic {
+            line_number: line_error.source_line.number,
+            column: line_error.command_column(),
+            severity: Severity::Error,
+            message: format!("{:#} ({} handler)", line_error.error, line_error.handler),
+        })
+        .collect();
+    let mut lines = (1..).zip(file_content.lines());
+    while let Some((line_number, line)) = lines.next() {
+        let source_line = SourceLine { number: line_number, text: line, file_path: None };
+        let left_trimmed_line = line.trim_start();
+        if left_trimmed_line.starts_with('#') {
+            continue;
+        }
+        diagnostics.extend(check_indentation(source_line));
+        if let Some(stripped_line) = left_trimmed_line.strip_prefix("COPY <<") {
+            // The heredoc lines are file contents, not instructions.
+            let delimiter_str = stripped_line.split(' ').next().unwrap_or_default();
+            let (delimiter, strip_tabs) = parse_heredoc_delimiter(delimiter_str);
+            lines
+                .by_ref()
+                .find(|(_, next_line)| strip_heredoc_tabs(next_line, strip_tabs) == delimiter);
+            continue;
+        }
+        // An invalid line is already reported.
+        if line_errors.iter().any(|line_error| line_error.source_line.number == line_number) {
+            continue;
+        }
+        if left_trimmed_line.contains("cargo install ") {
+            diagnostics.extend(check_cargo_install(source_line));
+        } else if left_trimmed_line.starts_with("pixi global install ") {
+            diagnostics.extend(check_pixi_global_install(source_line));
+        }
+    }
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line_number, diagnostic.column));
+    diagnostics
+}
+
+fn indentation_len(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn column_of_byte_index(line: &str, byte_index: usize) -> usize {
+    line[..byte_index].chars().count() + 1
+}
+
+fn warning(source_line: SourceLine, byte_index: usize, message: String) -> Diagnostic {
+    Diagnostic {
+        line_number: source_line.number,
+        column: column_of_byte_index(source_line.text, byte_index),
+        severity: Severity::Warning,
+        message,
+    }
+}
+
+fn check_indentation(source_line: SourceLine) -> Option<Diagnostic> {
+    let indentation = &source_line.text[..indentation_len(source_line.text)];
+    if !indentation.contains(' ') || !indentation.contains('\t') {
+        return None;
+    }
+    // The indentation contains a space so `unwrap()` is OK.
+    let first_char = indentation.chars().next().unwrap();
+    // The indentation contains both a space and a tab so `unwrap()` is OK.
+    let byte_index = indentation.find(|character| character != first_char).unwrap();
+    Some(warning(source_line, byte_index, "indentation mixes tabs and spaces".to_owned()))
+}
+
+fn check_cargo_install(source_line: SourceLine) -> Vec<Diagnostic> {
+    // The line contains "cargo install " so `unwrap()` is OK.
+    let byte_index = source_line.text.find("cargo install ").unwrap();
+    let command_str = &source_line.text[byte_index..];
+    let command_str = strip_command_suffix(command_str).unwrap_or(command_str);
+    let words: Vec<_> = command_str.split_whitespace().collect();
+    let crate_name = words.get(2).copied().unwrap_or_default();
+    // An option with a value can also be written like `--version=0.3.0`.
+    let has_option = |option: &str| {
+        words.iter().any(|word| {
+            word.strip_prefix(option).is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+        })
+    };
+    let mut diagnostics = Vec::new();
+    if !has_option("--locked") {
+        let message = format!("{} crate installed without --locked", quote(crate_name));
+        diagnostics.push(warning(source_line, byte_index, message));
+    }
+    if !["--version", "--tag", "--rev"].into_iter().any(has_option) {
+        let message =
+            format!("{} crate installed without --version, --tag or --rev", quote(crate_name));
+        diagnostics.push(warning(source_line, byte_index, message));
+    }
+    diagnostics
+}
+
+fn check_pixi_global_install(source_line: SourceLine) -> Option<Diagnostic> {
+    let left_trimmed_line = source_line.text.trim_start();
+    let stripped_line = left_trimmed_line.strip_prefix("pixi global install ")?;
+    let recipe_and_version = strip_command_suffix(stripped_line).unwrap_or(stripped_line);
+    let unpinned = match recipe_and_version.split_once('=') {
+        Some((recipe, version)) => {
+            version.is_empty() || version.contains('*') || recipe.ends_with(['<', '>', '!', '~'])
+        }
+        None => true,
+    };
+    let byte_index =
+        source_line.text.len() - left_trimmed_line.len() + "pixi global install ".len();
+    unpinned.then(|| {
+        let message = format!("unpinned pixi recipe {}", quote(recipe_and_version));
+        warning(source_line, byte_index, message)
+    })
+}
@@ -1,10 +1,22 @@
+mod annotation_handling;
+mod apt_handling;
 mod cargo_handling;
 mod command;
 mod command_computing;
 mod common;
+mod download_handling;
+mod flatpak_handling;
+mod formatting;
 mod git_handling;
+mod include_handling;
+mod linting;
 mod nonempty_str;
 mod pixi_handling;
+mod script_rendering;
+mod snap_handling;
+mod stage_handling;
+mod symlink_handling;
+mod variable_expansion;
 
 // Remark about the unit tests in separate files:
 // https://matklad.github.io/2021/02/27/delete-cargo-integration-tests.html#Assorted-Tricks
@@ -15,19 +27,38 @@ mod happy_path_tests;
 #[cfg(test)]
 mod parsing_error_tests;
 
+use std::collections::VecDeque;
 use std::fs;
-use std::io::Write as _;
+use std::io::{self, BufRead as _, BufReader, Read, Write};
+use std::iter;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::PermissionsExt as _;
 use std::path::{Path, PathBuf};
+use std::process::{ExitCode, Stdio};
+use std::sync::{Condvar, Mutex};
+use std::thread;
 
-use anyhow::{Context as _, bail};
+use anyhow::{Context as _, anyhow, bail, ensure};
 use clap::Parser;
+use itertools::Itertools as _;
 
-use command::Command;
-use command_computing::{compute_commands, parse_state_from_file_content};
-use common::quote_path;
+use annotation_handling::{AnnotationContext, Profile};
+use command::{Command, PlannedCommand, SourceLine};
+use command_computing::{State, compute_base_image_change, compute_commands};
+use common::{quote, quote_path, strip_command_suffix};
+use formatting::format_file_content;
+use include_handling::{
+    IncludedFile, find_include_chain, parse_state_with_includes, read_included_files,
+};
+use linting::{Severity, lint_file_content};
+use script_rendering::render_script;
+use variable_expansion::{
+    EnvScope, Expansion, child_environment, env_declarations, expand_variables, home_dir,
+    parse_build_arg,
+};
 
 #[derive(Parser)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 #[clap(verbatim_doc_comment)]
 /// Update what is installed by comparing two `Dockerfile`s.
 ///
@@ -73,14 +104,87 @@ use common::quote_path;
 /// sync_install <(git show :./Dockerfile) Dockerfile
 /// sync_install <(git show :./Dockerfile) Dockerfile --go
 /// ```
+///
+/// Note: `lint` and `fmt` are subcommands, so a `current_state` file with one of these names must
+/// be given with a directory, like `./lint`.
+///
+/// Exit codes:
+///
+/// - 0: nothing to do, or all the commands were executed
+/// - 1: a command failed
+/// - 2: invalid command-line arguments
+/// - 3: dry run or emitted script with planned commands, so the current state differs from the
+///   target state
+/// - 4: a `Dockerfile` could not be parsed, or `lint` found an error
+/// - 5: a file could not be read or written
+/// - 6: `lint` only found warnings
+#[expect(clippy::struct_excessive_bools, reason = "the command-line flags are independent")]
 struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Subcommand>,
     /// Dockerfile
-    current_state_file_path: PathBuf,
+    #[arg(required = true)]
+    current_state_file_path: Option<PathBuf>,
     /// Dockerfile
-    target_state_file_path: PathBuf,
+    #[arg(required = true)]
+    target_state_file_path: Option<PathBuf>,
     /// Cancel the dry run
     #[arg(long)]
     go: bool,
+    /// Cancel the dry run, but ask for a confirmation before executing each command
+    #[arg(long, conflicts_with = "go")]
+    interactive: bool,
+    /// With --go, execute the remaining commands after a failure and print a summary at the end.
+    /// The commands depending on a failed one, like `pixi global install` after a failed
+    /// `cargo install pixi`, are skipped.
+    #[arg(long, requires = "go")]
+    keep_going: bool,
+    /// With --go, number of commands executed at the same time
+    #[arg(long, default_value_t = NonZeroUsize::MIN, requires = "go")]
+    jobs: NonZeroUsize,
+    /// Print why each command is planned: the lines of both files and the difference of their
+    /// tokens
+    #[arg(long, conflicts_with_all = ["interactive", "keep_going", "jobs"])]
+    explain: bool,
+    /// Write the commands to a POSIX shell script instead of printing them
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["go", "interactive", "explain"])]
+    emit_script: Option<PathBuf>,
+    /// Override the default value of an `ARG` instruction in both files
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_build_arg)]
+    build_arg: Vec<(String, String)>,
+    /// Do not give the `HOME` of the `ENV` instructions to the executed commands, which keep the
+    /// `HOME` of this process
+    #[arg(long)]
+    ignore_env_home: bool,
+    /// Compare the actions of the stage with this name in both files, instead of the last stage
+    #[arg(long, value_name = "NAME")]
+    stage: Option<String>,
+    /// Manage the lines annotated with `# sync_install: only=PROFILE` only for this profile
+    #[arg(long, value_enum, default_value_t = Profile::Host)]
+    profile: Profile,
+    /// Dockerfile layered on the target state file: its actions replace the ones with the same
+    /// key. With several overlays, the later ones win.
+    #[arg(long, value_name = "PATH")]
+    overlay: Vec<PathBuf>,
+    /// Reinstall all the crates with `cargo install --force` when the Rust version of the base
+    /// image changes, like from `rust:1.97.1-slim-bookworm` to `rust:1.98.0-slim-bookworm`, or of
+    /// the last `rust` stage before, like a builder stage
+    #[arg(long)]
+    rebuild_on_base_change: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Report all the problems of a `Dockerfile` with `file:line:col` diagnostics
+    Lint {
+        /// Dockerfile
+        file_path: PathBuf,
+    },
+    /// Rewrite the lines managed by `sync_install` in a `Dockerfile` in a canonical format
+    Fmt {
+        /// Dockerfile
+        file_path: PathBuf,
+    },
 }
 
 macro_rules! my_writeln {
@@ -89,57 +193,759 @@ macro_rules! my_writeln {
     };
 }
 
-fn main() -> anyhow::Result<()> {
+/// Exit code of a dry run with planned commands
+const CHANGES_PLANNED_EXIT_CODE: u8 = 3;
+
+/// Exit code of `lint` when there are warnings but no error
+const LINT_WARNINGS_EXIT_CODE: u8 = 6;
+
+/// Kind of error, which determines the exit code
+#[derive(Clone, Copy)]
+enum ErrorKind {
+    Execution,
+    Parse,
+    Io,
+}
+
+impl ErrorKind {
+    const fn exit_code(self) -> u8 {
+        match self {
+            Self::Execution => 1,
+            Self::Parse => 4,
+            Self::Io => 5,
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    let current_state_file_path = &cli.current_state_file_path;
-    let target_state_file_path = &cli.target_state_file_path;
-    let dry_run = !cli.go;
-    if dry_run {
-        my_writeln!("This is a dry run. Add the --go option to execute the below command(s).")?;
-    }
-    let data = get_input_data(current_state_file_path, target_state_file_path)?;
-    let current_state = parse_state_from_file_content(&data.current_state_file_content)
-        .with_context(|| {
-            format!("failed to parse the content of {}", quote_path(current_state_file_path))
-        })?;
-    let target_state = parse_state_from_file_content(&data.target_state_file_content)
-        .with_context(|| {
-            format!("failed to parse the content of {}", quote_path(target_state_file_path))
-        })?;
-    compute_commands(&current_state, &target_state)
-        .try_for_each(|command| print_and_execute(&command, dry_run))
-}
-
-struct InputData {
-    current_state_file_content: String,
-    target_state_file_content: String,
-}
-
-fn get_input_data(
-    current_state_file_path: &Path,
-    target_state_file_path: &Path,
-) -> anyhow::Result<InputData> {
-    let current_state_file_content = fs::read_to_string(current_state_file_path)
-        .with_context(|| format!("failed to read {}", quote_path(current_state_file_path)))?;
-    let target_state_file_content = fs::read_to_string(target_state_file_path)
-        .with_context(|| format!("failed to read {}", quote_path(target_state_file_path)))?;
-    Ok(InputData { current_state_file_content, target_state_file_content })
-}
-
-fn print_and_execute(command: &Command, dry_run: bool) -> anyhow::Result<()> {
+    let result = match &cli.subcommand {
+        Some(Subcommand::Lint { file_path }) => lint(file_path),
+        Some(Subcommand::Fmt { file_path }) => format(file_path),
+        None => run(&cli),
+    };
+    match result {
+        Ok(exit_code) => exit_code,
+        Err((kind, error)) => {
+            // If stderr is not writable, there is nothing more to do.
+            #[expect(clippy::use_debug, reason = "same format as if `main` returned the error")]
+            let _unwritable_stderr = writeln!(io::stderr(), "Error: {error:?}");
+            ExitCode::from(kind.exit_code())
+        }
+    }
+}
+
+fn lint(file_path: &Path) -> Result<ExitCode, (ErrorKind, anyhow::Error)> {
+    let io_error = |error| (ErrorKind::Io, error);
+    let file_content = fs::read_to_string(file_path)
+        .with_context(|| format!("failed to read {}", quote_path(file_path)))
+        .map_err(io_error)?;
+    let diagnostics = lint_file_content(&file_content);
+    for diagnostic in &diagnostics {
+        let (line_number, column) = (diagnostic.line_number, diagnostic.column);
+        my_writeln!(
+            "{}:{line_number}:{column}: {}: {}",
+            quote_path(file_path),
+            diagnostic.severity.as_str(),
+            diagnostic.message
+        )
+        .map_err(io_error)?;
+    }
+    let worst_severity = diagnostics.iter().map(|diagnostic| diagnostic.severity).min();
+    Ok(match worst_severity {
+        Some(Severity::Error) => ExitCode::from(ErrorKind::Parse.exit_code()),
+        Some(Severity::Warning) => ExitCode::from(LINT_WARNINGS_EXIT_CODE),
+        None => ExitCode::SUCCESS,
+    })
+}
+
+fn format(file_path: &Path) -> Result<ExitCode, (ErrorKind, anyhow::Error)> {
+    let io_error = |error| (ErrorKind::Io, error);
+    let file_content = fs::read_to_string(file_path)
+        .with_context(|| format!("failed to read {}", quote_path(file_path)))
+        .map_err(io_error)?;
+    let formatted_content = format_file_content(&file_content)
+        .with_context(|| format!("failed to format {}", quote_path(file_path)))
+        .map_err(|error| (ErrorKind::Parse, error))?;
+    if formatted_content != file_content {
+        fs::write(file_path, formatted_content)
+            .with_context(|| format!("failed to write {}", quote_path(file_path)))
+            .map_err(io_error)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run(cli: &Cli) -> Result<ExitCode, (ErrorKind, anyhow::Error)> {
+    // Without subcommand, the file paths are required, so `unwrap()` is OK.
+    let current_state_file_path = cli.current_state_file_path.as_deref().unwrap();
+    let target_state_file_path = cli.target_state_file_path.as_deref().unwrap();
+    let dry_run = !cli.go && !cli.interactive;
+    let explained_file_paths =
+        cli.explain.then_some([current_state_file_path, target_state_file_path]);
+    // The explanation already names the file of each target state line.
+    let layered_file_path =
+        (!cli.overlay.is_empty() && !cli.explain).then_some(target_state_file_path);
+    let io_error = |error| (ErrorKind::Io, error);
+    let parse_error = |error| (ErrorKind::Parse, error);
+    let execution_error = |error| (ErrorKind::Execution, error);
+    if dry_run && cli.emit_script.is_none() {
+        my_writeln!("This is a dry run. Add the --go option to execute the below command(s).")
+            .map_err(io_error)?;
+    }
+    let current_state_file = StateFile::read(current_state_file_path, &cli.build_arg)?;
+    let target_state_file = StateFile::read(target_state_file_path, &cli.build_arg)?;
+    let overlay_files: Vec<_> = cli
+        .overlay
+        .iter()
+        .map(|overlay_file_path| StateFile::read(overlay_file_path, &cli.build_arg))
+        .try_collect()?;
+    let context = AnnotationContext::of_host(cli.profile);
+    let current_state_result = current_state_file.parse(cli.stage.as_deref(), &context);
+    let target_state_result =
+        parse_layered_state(&target_state_file, &overlay_files, cli.stage.as_deref(), &context);
+    // If both files are invalid, both are reported.
+    let (current_state, target_state) = match (current_state_result, target_state_result) {
+        (Ok(current_state), Ok(target_state)) => (current_state, target_state),
+        (Err(error), Ok(_)) | (Ok(_), Err(error)) => return Err(parse_error(error)),
+        (Err(current_state_error), Err(target_state_error)) => {
+            return Err(parse_error(anyhow!("{current_state_error:#}\n{target_state_error:#}")));
+        }
+    };
+    let base_image_change = compute_base_image_change(&current_state, &target_state);
+    if let (Some([current_base_image, target_base_image]), None) =
+        (base_image_change, &cli.emit_script)
+    {
+        my_writeln!(
+            "Base image changed: [{}] -> [{}]",
+            current_base_image.as_str(),
+            target_base_image.as_str()
+        )
+        .map_err(io_error)?;
+    }
+    let mut planned_commands =
+        compute_commands(&current_state, &target_state, cli.rebuild_on_base_change)
+            .map(|planned_command| {
+                let env_scopes =
+                    command_env_scopes(&target_state_file, &overlay_files, &planned_command);
+                let environment = env_declarations(&env_scopes, cli.ignore_env_home);
+                planned_command.with_environment(environment)
+            })
+            .peekable();
+    let changes_planned = dry_run && planned_commands.peek().is_some();
+    let exit_code =
+        if changes_planned { ExitCode::from(CHANGES_PLANNED_EXIT_CODE) } else { ExitCode::SUCCESS };
+    if let Some(script_path) = &cli.emit_script {
+        let script =
+            render_script(planned_commands, current_state_file_path, target_state_file_path);
+        fs::write(script_path, script)
+            .and_then(|()| fs::set_permissions(script_path, fs::Permissions::from_mode(0o755)))
+            .with_context(|| format!("failed to write {}", quote_path(script_path)))
+            .map_err(io_error)?;
+        return Ok(exit_code);
+    }
+    // The errors while executing are mostly command failures, so they all have the same kind.
+    if cli.interactive {
+        ask_and_execute(planned_commands, layered_file_path).map_err(execution_error)?;
+    } else if cli.jobs.get() > 1 {
+        let planned_commands: Vec<_> = planned_commands.collect();
+        execute_in_parallel(&planned_commands, cli.jobs, cli.keep_going, layered_file_path)
+            .map_err(execution_error)?;
+    } else if cli.keep_going {
+        execute_and_keep_going(planned_commands, layered_file_path).map_err(execution_error)?;
+    } else if dry_run {
+        planned_commands
+            .try_for_each(|planned_command| {
+                print_and_execute(
+                    &planned_command,
+                    explained_file_paths,
+                    layered_file_path,
+                    dry_run,
+                )
+            })
+            .map_err(io_error)?;
+    } else {
+        planned_commands
+            .try_for_each(|planned_command| {
+                print_and_execute(
+                    &planned_command,
+                    explained_file_paths,
+                    layered_file_path,
+                    dry_run,
+                )
+            })
+            .map_err(execution_error)?;
+    }
+    Ok(exit_code)
+}
+
+/// `Dockerfile` with its variables expanded, and the files it includes
+struct StateFile<'a> {
+    path: &'a Path,
+    expansion: Expansion,
+    included_files: Vec<IncludedFile>,
+}
+
+impl<'a> StateFile<'a> {
+    /// Read the file and the files it includes. An include cycle is a parse error.
+    fn read(
+        path: &'a Path,
+        build_args: &[(String, String)],
+    ) -> Result<Self, (ErrorKind, anyhow::Error)> {
+        let file_content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", quote_path(path)))
+            .map_err(|error| (ErrorKind::Io, error))?;
+        let expansion = expand_variables(&file_content, build_args);
+        let included_files = read_included_files(path, &expansion.file_content, build_args)
+            .map_err(|error| (ErrorKind::Io, error))?
+            .with_context(|| format!("failed to parse the content of {}", quote_path(path)))
+            .map_err(|error| (ErrorKind::Parse, error))?;
+        Ok(Self { path, expansion, included_files })
+    }
+    fn parse(
+        &self,
+        stage_name: Option<&str>,
+        context: &AnnotationContext,
+    ) -> anyhow::Result<State<'_>> {
+        let file_content = &self.expansion.file_content;
+        let included_files = &self.included_files;
+        parse_state_with_includes(self.path, file_content, included_files, stage_name, context)
+            .with_context(|| format!("failed to parse the content of {}", quote_path(self.path)))
+    }
+    /// Return the `ENV` scopes of a line of this file or of a file it includes: the `ENV` variables
+    /// of each including file before its include directive, then the ones before the line. Return
+    /// `None` if the line is in none of these files.
+    fn env_scopes(&self, source_line: SourceLine) -> Option<Vec<EnvScope<'_>>> {
+        let include_chain = match source_line.file_path.filter(|path| *path != self.path) {
+            Some(file_path) => find_include_chain(&self.included_files, file_path)?,
+            None => Vec::new(),
+        };
+        let env_variable_lists = iter::once(&self.expansion.env_variables).chain(
+            include_chain.iter().map(|included_file| &included_file.expansion.env_variables),
+        );
+        let line_numbers = include_chain
+            .iter()
+            .map(|included_file| included_file.line_number)
+            .chain([source_line.number]);
+        Some(
+            env_variable_lists
+                .zip(line_numbers)
+                .map(|(env_variables, line_number)| EnvScope {
+                    env_variables,
+                    line_number: Some(line_number),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Return the `ENV` scopes of a planned command. A command of an overlay gets all the `ENV`
+/// variables of the target state file first. A command without line in the target state, like a
+/// removal, gets all of them only.
+fn command_env_scopes<'a>(
+    target_state_file: &'a StateFile,
+    overlay_files: &'a [StateFile],
+    planned_command: &PlannedCommand,
+) -> Vec<EnvScope<'a>> {
+    let env_variables = &target_state_file.expansion.env_variables;
+    let target_env_scope = EnvScope { env_variables, line_number: None };
+    let Some(source_line) = planned_command.target_state_line else {
+        return vec![target_env_scope];
+    };
+    if let Some(env_scopes) = target_state_file.env_scopes(source_line) {
+        return env_scopes;
+    }
+    let overlay_env_scopes =
+        overlay_files.iter().find_map(|overlay_file| overlay_file.env_scopes(source_line));
+    iter::once(target_env_scope).chain(overlay_env_scopes.into_iter().flatten()).collect()
+}
+
+/// Parse the target state file, then layer the overlays in order.
+fn parse_layered_state<'a>(
+    target_state_file: &'a StateFile,
+    overlay_files: &'a [StateFile],
+    stage_name: Option<&str>,
+    context: &AnnotationContext,
+) -> anyhow::Result<State<'a>> {
+    let mut state = target_state_file.parse(stage_name, context)?;
+    for overlay_file in overlay_files {
+        state.overlay(overlay_file.path, overlay_file.parse(stage_name, context)?);
+    }
+    Ok(state)
+}
+
+fn print_and_execute(
+    planned_command: &PlannedCommand,
+    explained_file_paths: Option<[&Path; 2]>,
+    layered_file_path: Option<&Path>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let command = &planned_command.command;
     my_writeln!("---> [{}]", command.display())?;
+    if let Some(file_paths) = explained_file_paths {
+        print_explanation(planned_command, file_paths)?;
+    }
+    if let Some(layer_file_path) = command_layer(planned_command, layered_file_path) {
+        my_writeln!("     layer: {}", quote_path(layer_file_path))?;
+    }
     if !dry_run {
-        execute(command)?;
+        execute(command, &planned_command.environment)?;
+    }
+    Ok(())
+}
+
+/// With overlays, return which layer the spec of the target state comes from.
+fn command_layer<'a>(
+    planned_command: &PlannedCommand<'a>,
+    layered_file_path: Option<&'a Path>,
+) -> Option<&'a Path> {
+    let target_state_file_path = layered_file_path?;
+    let SourceLine { file_path, .. } = planned_command.target_state_line?;
+    Some(file_path.unwrap_or(target_state_file_path))
+}
+
+fn print_explanation(
+    planned_command: &PlannedCommand,
+    [current_state_file_path, target_state_file_path]: [&Path; 2],
+) -> anyhow::Result<()> {
+    my_writeln!("     reason: {}", planned_command.reason)?;
+    let current_state_line = planned_command.current_state_line;
+    let target_state_line = planned_command.target_state_line;
+    for (file_path, source_line) in
+        [(current_state_file_path, current_state_line), (target_state_file_path, target_state_line)]
+    {
+        if let Some(SourceLine { number, text, file_path: included_file_path }) = source_line {
+            let file_path = included_file_path.unwrap_or(file_path);
+            my_writeln!("     {}:{number}: {}", quote_path(file_path), text.trim())?;
+        }
+    }
+    if let (Some(current_state_line), Some(target_state_line)) =
+        (current_state_line, target_state_line)
+    {
+        let current_tokens = spec_tokens(current_state_line.text);
+        let target_tokens = spec_tokens(target_state_line.text);
+        let removed_tokens = current_tokens.iter().filter(|token| !target_tokens.contains(token));
+        let added_tokens = target_tokens.iter().filter(|token| !current_tokens.contains(token));
+        let token_diff = itertools::chain!(
+            removed_tokens.map(|token| format!("-{}", quote(token))),
+            added_tokens.map(|token| format!("+{}", quote(token))),
+        )
+        .join(" ");
+        if !token_diff.is_empty() {
+            my_writeln!("     tokens: {token_diff}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Split the line into tokens, without the `; \` suffix of the managed commands.
+fn spec_tokens(line: &str) -> Vec<&str> {
+    let trimmed_line = line.trim();
+    strip_command_suffix(trimmed_line).unwrap_or(trimmed_line).split(' ').collect()
+}
+
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+fn ask_and_execute<'a>(
+    planned_commands: impl Iterator<Item = PlannedCommand<'a>>,
+    layered_file_path: Option<&'a Path>,
+) -> anyhow::Result<()> {
+    // After "all" or "quit", the answer is the same for the remaining commands.
+    let mut answer_for_the_remaining_commands = None;
+    let mut executed_command_count = 0;
+    let mut skipped_commands = Vec::new();
+    for planned_command in planned_commands {
+        let PlannedCommand { command, reason, environment, .. } = &planned_command;
+        my_writeln!("---> [{}] ({reason})", command.display())?;
+        if let Some(layer_file_path) = command_layer(&planned_command, layered_file_path) {
+            my_writeln!("     layer: {}", quote_path(layer_file_path))?;
+        }
+        let confirmed = if let Some(confirmed) = answer_for_the_remaining_commands {
+            confirmed
+        } else {
+            match ask_confirmation()? {
+                Answer::Yes => true,
+                Answer::No => false,
+                Answer::All => *answer_for_the_remaining_commands.insert(true),
+                Answer::Quit => *answer_for_the_remaining_commands.insert(false),
+            }
+        };
+        if !confirmed {
+            skipped_commands.push(planned_command);
+            continue;
+        }
+        if let Err(error) = execute(command, environment) {
+            print_report(executed_command_count, &skipped_commands)?;
+            return Err(error);
+        }
+        executed_command_count += 1;
+    }
+    print_report(executed_command_count, &skipped_commands)
+}
+
+fn ask_confirmation() -> anyhow::Result<Answer> {
+    loop {
+        let mut stdout = io::stdout();
+        write!(stdout, "Execute it? [y]es, [n]o, [a]ll, [q]uit: ")
+            .and_then(|()| stdout.flush())
+            .context("failed to write to stdout")?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).context("failed to read stdin")? == 0 {
+            // End of file: nothing more will be confirmed.
+            my_writeln!("")?;
+            return Ok(Answer::Quit);
+        }
+        match line.trim() {
+            "y" | "yes" => return Ok(Answer::Yes),
+            "n" | "no" => return Ok(Answer::No),
+            "a" | "all" => return Ok(Answer::All),
+            "q" | "quit" => return Ok(Answer::Quit),
+            _ => {}
+        }
+    }
+}
+
+fn print_report(
+    executed_command_count: usize,
+    skipped_commands: &[PlannedCommand],
+) -> anyhow::Result<()> {
+    if skipped_commands.is_empty() {
+        return my_writeln!("Executed command(s): {executed_command_count}. No skipped command.");
     }
+    my_writeln!(
+        "Executed command(s): {executed_command_count}. Skipped command(s): {}. \
+        So the target state is only partially applied. The skipped command(s) are:",
+        skipped_commands.len()
+    )?;
+    skipped_commands.iter().try_for_each(|PlannedCommand { command, reason, .. }| {
+        my_writeln!("---> [{}] ({reason})", command.display())
+    })
+}
+
+/// Number of lines of the standard error kept for the summary of `--keep-going`
+const STDERR_TAIL_LINE_COUNT: usize = 5;
+
+struct Failure<'a> {
+    command: Command<'a>,
+    status: String,
+    stderr_tail: VecDeque<String>,
+}
+
+fn execute_and_keep_going<'a>(
+    planned_commands: impl Iterator<Item = PlannedCommand<'a>>,
+    layered_file_path: Option<&'a Path>,
+) -> anyhow::Result<()> {
+    let mut succeeded_command_count = 0;
+    let mut failures = Vec::new();
+    let mut skipped_commands = Vec::new();
+    // The failed and skipped commands, which the next ones may depend on
+    let mut unsuccessful_commands = Vec::new();
+    for planned_command in planned_commands {
+        let command = planned_command.command.clone();
+        if depends_on_any(&planned_command, &unsuccessful_commands) {
+            skipped_commands.push(command);
+            unsuccessful_commands.push(planned_command);
+            continue;
+        }
+        my_writeln!("---> [{}]", command.display())?;
+        if let Some(layer_file_path) = command_layer(&planned_command, layered_file_path) {
+            my_writeln!("     layer: {}", quote_path(layer_file_path))?;
+        }
+        match execute_and_capture_stderr_tail(&command, &planned_command.environment, None)? {
+            Ok(()) => succeeded_command_count += 1,
+            Err((status, stderr_tail)) => {
+                failures.push(Failure { command, status, stderr_tail });
+                unsuccessful_commands.push(planned_command);
+            }
+        }
+    }
+    print_summary(succeeded_command_count, &failures, &skipped_commands)?;
+    ensure!(failures.is_empty(), "{} command(s) failed", failures.len());
     Ok(())
 }
 
-fn execute(command: &Command) -> anyhow::Result<()> {
-    let (program, args) = command.split_program_and_args();
-    std::process::Command::new(program)
-        .args(args)
-        .status()
-        .context("failed to execute process")
+/// Tell if a command must be skipped because it must keep its order with a failed or skipped one,
+/// like `pixi global install` after a failed `cargo install pixi`.
+fn depends_on_any<'a>(
+    planned_command: &PlannedCommand,
+    unsuccessful_commands: impl IntoIterator<Item = &'a PlannedCommand<'a>>,
+) -> bool {
+    unsuccessful_commands
+        .into_iter()
+        .any(|unsuccessful_command| planned_command.must_keep_order_with(unsuccessful_command))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Pending,
+    Running,
+    Done,
+    /// Not executed because it depends on a failed job
+    Skipped,
+}
+
+struct Scheduler<'a> {
+    job_states: Vec<JobState>,
+    failures: Vec<(usize, Failure<'a>)>,
+    stopped: bool,
+}
+
+impl<'a> Scheduler<'a> {
+    /// Return the first pending job which does not wait for an unfinished job with the same lock
+    /// or whose program is installed or removed by the other one.
+    fn next_job_index(&self, planned_commands: &[PlannedCommand]) -> Option<usize> {
+        (0..planned_commands.len()).find(|&index| {
+            let planned_command = &planned_commands[index];
+            self.job_states[index] == JobState::Pending
+                && (0..index).all(|previous_index| {
+                    let previous_planned_command = &planned_commands[previous_index];
+                    matches!(self.job_states[previous_index], JobState::Done | JobState::Skipped)
+                        || (planned_command
+                            .lock
+                            .is_none_or(|lock| previous_planned_command.lock != Some(lock))
+                            && !planned_command.must_keep_order_with(previous_planned_command))
+                })
+        })
+    }
+    /// After a failure, skip the pending jobs which depend on the failed one, directly or through
+    /// a skipped one. They are still pending because they wait for it.
+    fn finish_job(
+        &mut self,
+        index: usize,
+        failure: Option<Failure<'a>>,
+        keep_going: bool,
+        planned_commands: &[PlannedCommand],
+    ) {
+        self.job_states[index] = JobState::Done;
+        let Some(failure) = failure else {
+            return;
+        };
+        self.failures.push((index, failure));
+        self.stopped |= !keep_going;
+        for pending_index in 0..planned_commands.len() {
+            let unsuccessful_commands = (0..pending_index)
+                .filter(|&previous_index| {
+                    self.job_states[previous_index] == JobState::Skipped
+                        || self
+                            .failures
+                            .iter()
+                            .any(|(failed_index, _)| *failed_index == previous_index)
+                })
+                .map(|previous_index| &planned_commands[previous_index]);
+            if self.job_states[pending_index] == JobState::Pending
+                && depends_on_any(&planned_commands[pending_index], unsuccessful_commands)
+            {
+                self.job_states[pending_index] = JobState::Skipped;
+            }
+        }
+    }
+}
+
+/// Execute the commands with `job_count` worker threads. The output of each command is prefixed
+/// with its job number, which is its position in the plan.
+fn execute_in_parallel(
+    planned_commands: &[PlannedCommand],
+    job_count: NonZeroUsize,
+    keep_going: bool,
+    layered_file_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let scheduler = Mutex::new(Scheduler {
+        job_states: vec![JobState::Pending; planned_commands.len()],
+        failures: Vec::new(),
+        stopped: false,
+    });
+    let job_state_changed = Condvar::new();
+    let work = || -> anyhow::Result<()> {
+        loop {
+            let index = {
+                // A poisoned mutex means that another worker panicked so `unwrap()` is OK.
+                let mut guard = scheduler.lock().unwrap();
+                loop {
+                    if guard.stopped || !guard.job_states.contains(&JobState::Pending) {
+                        return Ok(());
+                    }
+                    if let Some(index) = guard.next_job_index(planned_commands) {
+                        guard.job_states[index] = JobState::Running;
+                        break index;
+                    }
+                    guard = job_state_changed.wait(guard).unwrap();
+                }
+            };
+            let planned_command = &planned_commands[index];
+            let PlannedCommand { command, environment, .. } = planned_command;
+            let layer_file_path = command_layer(planned_command, layered_file_path);
+            let result = my_writeln!("[{}] ---> [{}]", index + 1, command.display())
+                .and_then(|()| match layer_file_path {
+                    Some(layer_file_path) => {
+                        my_writeln!("[{}]      layer: {}", index + 1, quote_path(layer_file_path))
+                    }
+                    None => Ok(()),
+                })
+                .and_then(|()| {
+                    execute_and_capture_stderr_tail(command, environment, Some(index + 1))
+                });
+            let failure = match result {
+                Ok(result) => result.err().map(|(status, stderr_tail)| Failure {
+                    command: command.clone(),
+                    status,
+                    stderr_tail,
+                }),
+                Err(error) => {
+                    scheduler.lock().unwrap().stopped = true;
+                    job_state_changed.notify_all();
+                    return Err(error);
+                }
+            };
+            scheduler.lock().unwrap().finish_job(index, failure, keep_going, planned_commands);
+            job_state_changed.notify_all();
+        }
+    };
+    thread::scope(|scope| {
+        let mut workers = Vec::with_capacity(job_count.get());
+        for _ in 0..job_count.get() {
+            workers.push(scope.spawn(work));
+        }
+        // A worker panic is propagated so `unwrap()` is OK.
+        workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+    })?;
+    let Scheduler { job_states, mut failures, .. } = scheduler.into_inner().unwrap();
+    failures.sort_unstable_by_key(|(index, _)| *index);
+    let failures: Vec<_> = failures.into_iter().map(|(_, failure)| failure).collect();
+    if keep_going {
+        let done_job_count = job_states.iter().filter(|&&state| state == JobState::Done).count();
+        let skipped_commands: Vec<_> = (0..job_states.len())
+            .filter(|&index| job_states[index] == JobState::Skipped)
+            .map(|index| planned_commands[index].command.clone())
+            .collect();
+        print_summary(done_job_count - failures.len(), &failures, &skipped_commands)?;
+        ensure!(failures.is_empty(), "{} command(s) failed", failures.len());
+    } else if let Some(Failure { command, status, .. }) = failures.first() {
+        bail!("failed to run [{}]: {status}", command.display());
+    }
+    Ok(())
+}
+
+/// Like `execute`, but the standard error of the child process is forwarded line by line and
+/// its last lines are kept. With a job number, the standard output is also forwarded line by line
+/// and the lines are prefixed.
+///
+/// The outer error is about this process, the inner one is about the command.
+fn execute_and_capture_stderr_tail(
+    command: &Command,
+    environment: &[(String, String)],
+    job_number: Option<usize>,
+) -> anyhow::Result<Result<(), (String, VecDeque<String>)>> {
+    let mut stderr_tail = VecDeque::with_capacity(STDERR_TAIL_LINE_COUNT);
+    let environment = child_environment(environment);
+    let home = home_dir(&environment);
+    if let Err(error) =
+        command.precondition().map_or(Ok(()), |precondition| precondition.check(home.as_deref()))
+    {
+        return Ok(Err((format!("{error:#}"), stderr_tail)));
+    }
+    let (program, _) = command.split_program_and_args();
+    let mut std_command = std::process::Command::new(program);
+    std_command
+        .args(command.process_args(home.as_deref()))
+        .envs(environment)
+        .stderr(Stdio::piped());
+    if job_number.is_some() {
+        std_command.stdout(Stdio::piped());
+    }
+    let mut child = match std_command.spawn() {
+        Ok(child) => child,
+        Err(error) => return Ok(Err((format!("failed to execute process: {error}"), stderr_tail))),
+    };
+    let prefix = job_number.map_or_else(String::new, |job_number| format!("[{job_number}] "));
+    let child_stdout = child.stdout.take();
+    thread::scope(|scope| {
+        let stdout_forwarder = child_stdout.map(|child_stdout| {
+            scope.spawn(|| {
+                forward_lines(child_stdout, &mut io::stdout(), &prefix, |_| {})
+                    .context("failed to forward the child stdout")
+            })
+        });
+        // `stderr` is piped so `unwrap()` is OK.
+        let child_stderr = child.stderr.take().unwrap();
+        forward_lines(child_stderr, &mut io::stderr(), &prefix, |line| {
+            if stderr_tail.len() == STDERR_TAIL_LINE_COUNT {
+                stderr_tail.pop_front();
+            }
+            stderr_tail.push_back(String::from_utf8_lossy(line).trim_end().to_owned());
+        })
+        .context("failed to forward the child stderr")?;
+        // A forwarder panic is propagated so `unwrap()` is OK.
+        stdout_forwarder.map_or(Ok(()), |forwarder| forwarder.join().unwrap())
+    })?;
+    let status = child.wait().context("failed to wait for the child process")?;
+    Ok(if status.success() { Ok(()) } else { Err((status.to_string(), stderr_tail)) })
+}
+
+/// Each line is written at once, so that the lines of parallel jobs are not mixed.
+fn forward_lines(
+    reader: impl Read,
+    writer: &mut impl Write,
+    prefix: &str,
+    mut on_line: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = prefix.as_bytes().to_vec();
+    // `read_until` returns the number of bytes read by this call, which is 0 at the end.
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        writer.write_all(&line)?;
+        on_line(&line[prefix.len()..]);
+        line.truncate(prefix.len());
+    }
+    Ok(())
+}
+
+fn print_summary(
+    succeeded_command_count: usize,
+    failures: &[Failure],
+    skipped_commands: &[Command],
+) -> anyhow::Result<()> {
+    if failures.is_empty() {
+        return my_writeln!("Succeeded command(s): {succeeded_command_count}. No failed command.");
+    }
+    my_writeln!(
+        "Succeeded command(s): {succeeded_command_count}. Failed command(s): {}:",
+        failures.len()
+    )?;
+    let status_width =
+        failures.iter().map(|failure| failure.status.len()).fold("STATUS".len(), usize::max);
+    my_writeln!("{:status_width$} | COMMAND AND STDERR TAIL", "STATUS")?;
+    failures.iter().try_for_each(|Failure { command, status, stderr_tail }| {
+        my_writeln!("{status:status_width$} | [{}]", command.display())?;
+        stderr_tail.iter().try_for_each(|line| my_writeln!("{:status_width$} |     {line}", ""))
+    })?;
+    if skipped_commands.is_empty() {
+        return Ok(());
+    }
+    my_writeln!("Skipped command(s), which depend on a failed one: {}:", skipped_commands.len())?;
+    skipped_commands.iter().try_for_each(|command| my_writeln!("---> [{}]", command.display()))
+}
+
+fn execute(command: &Command, environment: &[(String, String)]) -> anyhow::Result<()> {
+    let environment = child_environment(environment);
+    let home = home_dir(&environment);
+    let (program, _) = command.split_program_and_args();
+    command
+        .precondition()
+        .map_or(Ok(()), |precondition| precondition.check(home.as_deref()))
+        .and_then(|()| {
+            std::process::Command::new(program)
+                .args(command.process_args(home.as_deref()))
+                .envs(environment)
+                .status()
+                .context("failed to execute process")
+        })
         .and_then(|status| {
             if !status.success() {
                 bail!("error status: {status}");
@@ -173,11 +173,366 @@ fn same_git_global_option_in_a_previous_line() -> anyhow::Result<()> {
     )
 }
 
+#[test]
+fn download_without_expected_suffix() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            curl -fsSL file:///tmp/gitalias.txt -o /root/.gitalias",
+        ["failed to parse line 2: ", r#"download line which does not end with "; \""#],
+    )
+}
+
+#[test]
+fn download_to_relative_path() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            wget file:///tmp/gitalias.txt -O .gitalias; \
+            true",
+        [
+            "failed to parse line 2: ",
+            r#"".gitalias" is neither an absolute path nor a path starting with "~/""#,
+        ],
+    )
+}
+
+#[test]
+fn same_download_path_in_a_previous_line() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            wget file:///tmp/a.txt -O /root/.gitalias; \
+            curl -fsSL file:///tmp/b.txt -o /root/.gitalias; \
+            true",
+        [
+            "failed to parse line 3: ",
+            r#""/root/.gitalias" file already downloaded in a previous line"#,
+        ],
+    )
+}
+
+#[test]
+fn sha256_check_without_download() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r#"RUN set -eux; \
+            echo "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa  /root/.gitalias" | sha256sum -c; \
+            true"#,
+        ["failed to parse line 2: ", r#""/root/.gitalias" file not downloaded in a previous line"#],
+    )
+}
+
+#[test]
+fn sha256_check_with_invalid_sha256() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r#"RUN set -eux; \
+            wget file:///tmp/gitalias.txt -O /root/.gitalias; \
+            echo "abc  /root/.gitalias" | sha256sum -c; \
+            true"#,
+        ["failed to parse line 3: ", r#""abc" is not a SHA-256 in hexadecimal"#],
+    )
+}
+
+#[test]
+fn copy_heredoc_without_delimiter() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "COPY <<EOF /etc/apt/sources.list.d/vscodium.sources\nTypes: deb\n",
+        ["failed to parse line 1: ", r#"missing heredoc delimiter "EOF""#],
+    )
+}
+
+#[test]
+fn apt_source_with_unsupported_printf_conversion() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            printf 'Types: %s\n' > /etc/apt/sources.list.d/vivaldi.sources; \
+            apt-get update",
+        ["failed to parse line 2: ", "unsupported printf conversion in "],
+    )
+}
+
+#[test]
+fn same_apt_file_in_a_previous_line() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            printf 'Types: deb\n' > /etc/apt/sources.list.d/vivaldi.sources; \
+            printf 'Types: deb\n' > /etc/apt/sources.list.d/vivaldi.sources; \
+            apt-get update",
+        [
+            "failed to parse line 3: ",
+            r#""/etc/apt/sources.list.d/vivaldi.sources" APT file already written in a previous line"#,
+        ],
+    )
+}
+
+#[test]
+fn snap_install_with_unsupported_option() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            snap install --devmode vlc; \
+            true",
+        ["failed to parse line 2: ", r#"unsupported snap option: "--devmode""#],
+    )
+}
+
+#[test]
+fn snap_install_with_several_names() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            snap install vlc gimp; \
+            true",
+        ["failed to parse line 2: ", r#""gimp" after the snap name"#],
+    )
+}
+
+#[test]
+fn same_snap_in_a_previous_line() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            snap install --channel=edge vlc; \
+            snap install vlc; \
+            true",
+        [
+            "failed to parse line 3: ",
+            r#""vlc" snap already installed in a previous line: "#,
+            "the command was [sudo snap install '--channel=edge' vlc]",
+        ],
+    )
+}
+
+#[test]
+fn flatpak_install_without_assume_yes() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            flatpak install flathub org.gimp.GIMP; \
+            true",
+        ["failed to parse line 2: ", "missing -y: the command would wait for a confirmation"],
+    )
+}
+
+#[test]
+fn flatpak_install_without_remote() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            flatpak install -y org.gimp.GIMP; \
+            true",
+        ["failed to parse line 2: ", "expected a remote and an application id"],
+    )
+}
+
+#[test]
+fn flatpak_update_without_install() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            flatpak update -y --commit=0123abcd org.gimp.GIMP; \
+            true",
+        [
+            "failed to parse line 2: ",
+            r#""org.gimp.GIMP" flatpak application not installed in a previous line"#,
+        ],
+    )
+}
+
+#[test]
+fn flatpak_update_of_an_application_from_several_remotes() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            flatpak install -y flathub org.gimp.GIMP; \
+            flatpak install -y flathub-beta org.gimp.GIMP; \
+            flatpak update -y --commit=0123abcd org.gimp.GIMP; \
+            true",
+        [
+            "failed to parse line 4: ",
+            r#""org.gimp.GIMP" flatpak application installed from several remotes in previous lines"#,
+        ],
+    )
+}
+
+#[test]
+fn flatpak_update_with_another_scope() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            flatpak install -y --user flathub org.gimp.GIMP; \
+            flatpak update -y --commit=0123abcd org.gimp.GIMP; \
+            true",
+        [
+            "failed to parse line 3: ",
+            r#""org.gimp.GIMP" flatpak application installed with another scope"#,
+        ],
+    )
+}
+
+#[test]
+fn ln_without_destination() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            ln -sfn /work/dotfiles/.bashrc; \
+            true",
+        ["failed to parse line 2: ", "expected a source and a destination"],
+    )
+}
+
+#[test]
+fn ln_with_relative_paths() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            ln -sfn dotfiles/.bashrc /root/.bashrc; \
+            ln -sfn /work/dotfiles/.vimrc .vimrc; \
+            true",
+        [
+            "failed to parse line 2: ",
+            r#""dotfiles/.bashrc" is neither an absolute path nor a path starting with "~/""#,
+            "failed to parse line 3: ",
+            r#"".vimrc" is neither an absolute path nor a path starting with "~/""#,
+        ],
+    )
+}
+
+#[test]
+fn same_symlink_in_a_previous_line() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            ln -sfn /work/dotfiles/.bashrc /root/.bashrc; \
+            ln -sfn /work/other_dotfiles/.bashrc /root/.bashrc; \
+            true",
+        [
+            "failed to parse line 3: ",
+            r#""/root/.bashrc" symbolic link already created in a previous line: "#,
+            r#"the source was "/work/dotfiles/.bashrc""#,
+        ],
+    )
+}
+
+#[test]
+fn all_the_invalid_lines_are_reported() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        r"RUN set -eux; \
+            cargo install ; \
+            pixi global install git=2.55.0; \
+            pixi global install fd; \
+            git config set --global user.name; \
+            cargo cache -r all",
+        [
+            "3 invalid lines:\n",
+            r#"failed to parse line 2: "            cargo install ; \" "#,
+            "(cargo install handler): empty crate name\n",
+            "failed to parse line 4: ",
+            "(pixi global install handler): '=' is missing\n",
+            "failed to parse line 5: ",
+            "(git config handler): ",
+        ],
+    )
+}
+
+#[test]
+fn same_stage_name_in_a_previous_line() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "FROM docker.io/library/rust:1.97.1-slim-bookworm AS builder\n\
+        FROM docker.io/library/debian:bookworm-slim AS BUILDER",
+        ["failed to parse line 2: ", r#""BUILDER" stage already declared in a previous line"#],
+    )
+}
+
+#[test]
+fn stage_name_missing_after_as() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "FROM docker.io/library/rust:1.97.1-slim-bookworm AS",
+        ["failed to parse line 1: ", r#""AS" must be followed by the stage name"#],
+    )
+}
+
+#[test]
+fn unknown_annotation() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "RUN set -eux; \\\n\
+        \x20   # sync_install: skip\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   true",
+        ["failed to parse line 2: ", "annotation handler", r#"unknown annotation "skip""#],
+    )
+}
+
+#[test]
+fn annotations_without_command() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "# sync_install: only=container\n\
+        RUN set -eux; \\\n\
+        \x20   # sync_install: ignore\n\
+        \x20   apt-get install -y vim; \\\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   true",
+        [
+            "2 invalid lines:\n",
+            "failed to parse line 1: ",
+            "failed to parse line 3: ",
+            "the next line is not a command which sync_install manages",
+        ],
+    )
+}
+
+#[test]
+fn unknown_profile_in_an_inline_annotation() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "RUN <<EOF\n\
+        cargo install fsays --version 0.3.0 --locked # sync_install: only=laptop\n\
+        EOF\n",
+        ["failed to parse line 2: ", r#"unknown profile "laptop": expected "host" or "container""#],
+    )
+}
+
+#[test]
+fn unknown_host_property() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "RUN set -eux; \\\n\
+        \x20   # sync_install: when os=linux\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   true",
+        [
+            "failed to parse line 2: ",
+            r#"unknown host property "os": expected "arch" or "hostname""#,
+        ],
+    )
+}
+
+#[test]
+fn condition_without_value() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "RUN set -eux; \\\n\
+        \x20   # sync_install: when hostname=~\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   true",
+        ["failed to parse line 2: ", r#"empty value in the condition "hostname=~""#],
+    )
+}
+
+#[test]
+fn unknown_stage() -> anyhow::Result<()> {
+    let result = parse_state_from_file_content(
+        "FROM docker.io/library/rust:1.97.1-slim-bookworm AS builder\n\
+        FROM docker.io/library/debian:bookworm-slim AS runtime",
+        Some("tester"),
+        None,
+    );
+    check_err_contains(result, [r#"no stage named "tester""#])
+}
+
+#[test]
+fn invalid_line_in_a_run_heredoc() -> anyhow::Result<()> {
+    parse_first_arg_and_check_error_contains(
+        "RUN <<EOF\n\
+        set -eux\n\
+        cargo install \n\
+        EOF\n",
+        [
+            r#"failed to parse line 3: "cargo install " "#,
+            "(cargo install handler)",
+            "empty crate name",
+        ],
+    )
+}
+
 fn parse_first_arg_and_check_error_contains<const N: usize>(
     file_content: &'static str,
     texts: [&'static str; N],
 ) -> anyhow::Result<()> {
-    let result = parse_state_from_file_content(file_content);
+    let result = parse_state_from_file_content(file_content, None, None);
     check_err_contains(result, texts)
 }
 
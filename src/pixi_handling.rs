@@ -2,8 +2,8 @@ use std::collections::HashMap;
 
 use anyhow::{Context as _, bail};
 
-use crate::command::{Command, command};
-use crate::common::quote;
+use crate::command::{PlannedCommand, command};
+use crate::common::{quote, strip_command_suffix};
 
 mod nonempty_str_types {
     crate::nonempty_str::newtype!(Recipe, error_msg = "empty recipe");
@@ -14,12 +14,22 @@ pub use nonempty_str_types::{Recipe, RecipeAndVersion};
 #[derive(Clone, Copy)]
 pub struct PixiGlobalInstall<'a>(Recipe<'a>, RecipeAndVersion<'a>);
 
+impl<'a> PixiGlobalInstall<'a> {
+    pub const fn key(self) -> Recipe<'a> {
+        self.0
+    }
+    /// Assume that the recipe installs a binary with the same name, like `git`.
+    pub const fn provided_program(self) -> &'a str {
+        self.0.as_str()
+    }
+}
+
 pub fn parse_stripped_line_with_pixi_global_install<'a>(
     stripped_line: &'a str,
     pixi_map: &mut HashMap<Recipe<'a>, RecipeAndVersion<'a>>,
 ) -> anyhow::Result<PixiGlobalInstall<'a>> {
     let expected_suffix = "; \\";
-    let Some(recipe_and_version_str) = stripped_line.strip_suffix(expected_suffix) else {
+    let Some(recipe_and_version_str) = strip_command_suffix(stripped_line) else {
         bail!(
             "line with \"pixi global install \" but which does not end with {}",
             quote(expected_suffix)
@@ -42,24 +52,35 @@ pub fn parse_stripped_line_with_pixi_global_install<'a>(
 pub fn compute_recipe_removal_command<'a>(
     target_state_pixi_map: &HashMap<Recipe<'a>, RecipeAndVersion<'a>>,
     current_state_action: PixiGlobalInstall<'a>,
-) -> Option<Command<'a>> {
+) -> Option<PlannedCommand<'a>> {
     let recipe = &current_state_action.0;
-    (!target_state_pixi_map.contains_key(recipe))
-        .then(|| command!["pixi", "global", "uninstall", recipe.as_str()].unwrap())
+    (!target_state_pixi_map.contains_key(recipe)).then(|| {
+        command!["pixi", "global", "uninstall", recipe.as_str()]
+            .unwrap()
+            .with_reason(format!("recipe {} removed from target", recipe.as_str()))
+    })
 }
 
 pub fn compute_recipe_install_or_update_command<'a>(
     current_state_pixi_map: &HashMap<Recipe<'a>, RecipeAndVersion<'a>>,
     target_state_action: PixiGlobalInstall<'a>,
-) -> Option<Command<'a>> {
+) -> Option<PlannedCommand<'a>> {
     let PixiGlobalInstall(recipe, target_state_recipe_and_version) = target_state_action;
-    current_state_pixi_map
-        .get(&recipe)
+    let current_state_recipe_and_version = current_state_pixi_map.get(&recipe);
+    current_state_recipe_and_version
         .is_none_or(|current_state_recipe_and_version| {
             current_state_recipe_and_version != &target_state_recipe_and_version
         })
         .then(|| {
             let recipe_and_version = target_state_recipe_and_version.as_str();
-            command!["pixi", "global", "install", recipe_and_version].unwrap()
+            let reason =
+                if let Some(current_state_recipe_and_version) = current_state_recipe_and_version {
+                    // The recipe is followed by '=' so the slicing is OK.
+                    let version = &recipe_and_version[recipe.as_str().len() + 1..];
+                    format!("{} -> {version}", current_state_recipe_and_version.as_str())
+                } else {
+                    format!("recipe {} added to target", recipe.as_str())
+                };
+            command!["pixi", "global", "install", recipe_and_version].unwrap().with_reason(reason)
         })
 }
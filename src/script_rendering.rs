@@ -0,0 +1,57 @@
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::command::{PlannedCommand, SourceLine};
+use crate::common::quote_path;
+use crate::variable_expansion::to_shell_word;
+
+/// Render the planned commands as a POSIX shell script which can be run on another machine. A
+/// command with `ENV` variables runs in a subshell which exports them, so that `$HOME`, `$PATH` and
+/// the `~` are resolved on the machine which runs the script, like when `sync_install` executes it.
+pub fn render_script<'a>(
+    planned_commands: impl Iterator<Item = PlannedCommand<'a>>,
+    current_state_file_path: &Path,
+    target_state_file_path: &Path,
+) -> String {
+    let mut script = String::new();
+    // `pipefail` is only POSIX since 2024, so the older shells run without it.
+    // Writing to a `String` cannot fail so `unwrap()` is OK.
+    writeln!(
+        script,
+        "#!/bin/sh\n\
+        # Generated by sync_install from {} to {}.\n\
+        set -eu\n\
+        if (set -o pipefail) 2>/dev/null; then set -o pipefail; fi",
+        quote_path(current_state_file_path),
+        quote_path(target_state_file_path)
+    )
+    .unwrap();
+    for planned_command in planned_commands {
+        writeln!(script, "\n# {}", planned_command.reason).unwrap();
+        for (file_path, source_line) in [
+            (current_state_file_path, planned_command.current_state_line),
+            (target_state_file_path, planned_command.target_state_line),
+        ] {
+            if let Some(SourceLine { number, text, file_path: included_file_path }) = source_line {
+                let file_path = included_file_path.unwrap_or(file_path);
+                writeln!(script, "# {}:{number}: {}", quote_path(file_path), text.trim()).unwrap();
+            }
+        }
+        let in_subshell = !planned_command.environment.is_empty();
+        let indent = if in_subshell { "    " } else { "" };
+        if in_subshell {
+            writeln!(script, "(").unwrap();
+        }
+        for (name, value) in &planned_command.environment {
+            writeln!(script, "{indent}export {name}={}", to_shell_word(value)).unwrap();
+        }
+        if let Some(precondition) = planned_command.command.precondition() {
+            writeln!(script, "{indent}{}", precondition.shell_check()).unwrap();
+        }
+        writeln!(script, "{indent}{}", planned_command.command.display()).unwrap();
+        if in_subshell {
+            writeln!(script, ")").unwrap();
+        }
+    }
+    script
+}
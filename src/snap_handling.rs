@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure};
+
+use crate::command::{Command, PlannedCommand, command};
+use crate::common::{quote, strip_command_suffix};
+
+mod nonempty_str_types {
+    crate::nonempty_str::newtype!(SnapName, error_msg = "empty snap name");
+    crate::nonempty_str::newtype!(SnapChannel, error_msg = "empty snap channel");
+}
+pub use nonempty_str_types::{SnapChannel, SnapName};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SnapSpec<'a> {
+    channel: Option<SnapChannel<'a>>,
+    classic: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct SnapInstall<'a>(SnapName<'a>, SnapSpec<'a>);
+
+impl<'a> SnapInstall<'a> {
+    pub const fn key(self) -> SnapName<'a> {
+        self.0
+    }
+}
+
+pub fn parse_stripped_line_with_snap_install<'a>(
+    stripped_line: &'a str,
+    snap_map: &mut HashMap<SnapName<'a>, SnapSpec<'a>>,
+) -> anyhow::Result<SnapInstall<'a>> {
+    let expected_suffix = "; \\";
+    let Some(options_and_name) = strip_command_suffix(stripped_line) else {
+        bail!("line with \"snap install \" but which does not end with {}", quote(expected_suffix));
+    };
+    let mut channel = None;
+    let mut classic = false;
+    let mut name = None;
+    for word in options_and_name.split(' ') {
+        ensure!(name.is_none(), "{} after the snap name", quote(word));
+        if word == "--classic" {
+            ensure!(!classic, "--classic given twice");
+            classic = true;
+        } else if let Some(channel_str) = word.strip_prefix("--channel=") {
+            ensure!(channel.is_none(), "--channel given twice");
+            channel = Some(SnapChannel::from_str(channel_str)?);
+        } else if word.starts_with('-') {
+            bail!("unsupported snap option: {}", quote(word));
+        } else {
+            name = Some(SnapName::from_str(word)?);
+        }
+    }
+    let Some(name) = name else {
+        bail!("empty snap name");
+    };
+    let spec = SnapSpec { channel, classic };
+    if let Some(previous_spec) = snap_map.insert(name, spec) {
+        bail!(
+            "{} snap already installed in a previous line: the command was [{}]",
+            quote(name.as_str()),
+            install_command(name, previous_spec).display()
+        );
+    }
+    Ok(SnapInstall(name, spec))
+}
+
+fn install_command<'a>(name: SnapName<'a>, spec: SnapSpec<'a>) -> Command<'a> {
+    let mut command = command!["sudo", "snap", "install"].unwrap();
+    if spec.classic {
+        command = command.concat_args(["--classic"]);
+    }
+    if let Some(channel) = spec.channel {
+        command = command.concat_args([format!("--channel={}", channel.as_str())]);
+    }
+    command.concat_args([name.as_str()])
+}
+
+pub fn compute_snap_removal_command<'a>(
+    target_state_snap_map: &HashMap<SnapName<'a>, SnapSpec<'a>>,
+    current_state_action: SnapInstall<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let name = current_state_action.0;
+    (!target_state_snap_map.contains_key(&name)).then(|| {
+        command!["sudo", "snap", "remove", name.as_str()]
+            .unwrap()
+            .with_reason(format!("snap {} removed from target", name.as_str()))
+    })
+}
+
+pub fn compute_snap_install_or_update_command<'a>(
+    current_state_snap_map: &HashMap<SnapName<'a>, SnapSpec<'a>>,
+    target_state_action: SnapInstall<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let SnapInstall(name, target_state_spec) = target_state_action;
+    let Some(current_state_spec) = current_state_snap_map.get(&name) else {
+        let reason = format!("snap {} added to target", name.as_str());
+        return Some(install_command(name, target_state_spec).with_reason(reason));
+    };
+    (current_state_spec != &target_state_spec).then(|| {
+        // Without `--channel`, `snap install` follows the default channel.
+        let channel = target_state_spec.channel.map_or("latest/stable", SnapChannel::as_str);
+        let channel_option = format!("--channel={channel}");
+        let mut command = command!["sudo", "snap", "refresh", channel_option].unwrap();
+        if target_state_spec.classic {
+            command = command.concat_args(["--classic"]);
+        }
+        let reason = format!(
+            "snap {}: [{}] -> [{}]",
+            name.as_str(),
+            install_command(name, *current_state_spec).display(),
+            install_command(name, target_state_spec).display()
+        );
+        command.concat_args([name.as_str()]).with_reason(reason)
+    })
+}
@@ -0,0 +1,69 @@
+use anyhow::{bail, ensure};
+
+use crate::common::quote;
+
+mod nonempty_str_types {
+    crate::nonempty_str::newtype!(StageName, error_msg = "empty stage name");
+    crate::nonempty_str::newtype!(BaseImage, error_msg = "empty base image");
+}
+pub use nonempty_str_types::{BaseImage, StageName};
+
+pub struct FromInstruction<'a> {
+    pub base_image: BaseImage<'a>,
+    pub stage_name: Option<StageName<'a>>,
+}
+
+impl StageName<'_> {
+    /// The stage names are case-insensitive, like with `docker build --target`.
+    pub fn matches(self, name: &str) -> bool {
+        self.as_str().eq_ignore_ascii_case(name)
+    }
+}
+
+impl<'a> BaseImage<'a> {
+    /// Version of the Rust toolchain of a `rust` image, like "1.97.1" for
+    /// `docker.io/library/rust:1.97.1-slim-bookworm`
+    pub fn rust_version(self) -> Option<&'a str> {
+        let name_and_tag = self.as_str().split('@').next().unwrap_or_default();
+        let (name, tag) = name_and_tag.rsplit_once(':')?;
+        // Without tag, the colon may be the one of a registry port, like in `localhost:5000/rust`.
+        if tag.contains('/') || name.rsplit('/').next() != Some("rust") {
+            return None;
+        }
+        let version = tag.split('-').next().unwrap_or_default();
+        version
+            .split('.')
+            .all(|number| !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()))
+            .then_some(version)
+    }
+}
+
+/// Parse `FROM [--platform=...] image [AS name]`.
+pub fn parse_stripped_line_with_from<'a>(
+    stripped_line: &'a str,
+    previous_stage_names: impl IntoIterator<Item = StageName<'a>>,
+) -> anyhow::Result<FromInstruction<'a>> {
+    let words: Vec<_> = stripped_line.split_whitespace().collect();
+    let (image_words, stage_name_str) = match words.as_slice() {
+        [image_words @ .., as_word, stage_name_str] if as_word.eq_ignore_ascii_case("AS") => {
+            (image_words, Some(*stage_name_str))
+        }
+        image_words => (image_words, None),
+    };
+    ensure!(
+        !image_words.iter().any(|word| word.eq_ignore_ascii_case("AS")),
+        "\"AS\" must be followed by the stage name at the end of the line"
+    );
+    let Some(base_image_str) = image_words.iter().find(|word| !word.starts_with("--")) else {
+        bail!("missing base image");
+    };
+    let base_image = BaseImage::from_str(base_image_str)?;
+    let Some(stage_name_str) = stage_name_str else {
+        return Ok(FromInstruction { base_image, stage_name: None });
+    };
+    let stage_name = StageName::from_str(stage_name_str)?;
+    if previous_stage_names.into_iter().any(|previous_name| previous_name.matches(stage_name_str)) {
+        bail!("{} stage already declared in a previous line", quote(stage_name_str));
+    }
+    Ok(FromInstruction { base_image, stage_name: Some(stage_name) })
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure};
+
+use crate::command::{PlannedCommand, Precondition, command};
+use crate::common::{quote, strip_command_suffix};
+
+mod nonempty_str_types {
+    crate::nonempty_str::newtype!(SymlinkSource, error_msg = "empty symbolic link source");
+    crate::nonempty_str::newtype!(SymlinkDest, error_msg = "empty symbolic link destination");
+}
+pub use nonempty_str_types::{SymlinkDest, SymlinkSource};
+
+#[derive(Clone, Copy)]
+pub struct CreateSymlink<'a>(SymlinkDest<'a>, SymlinkSource<'a>);
+
+impl<'a> CreateSymlink<'a> {
+    pub const fn key(self) -> SymlinkDest<'a> {
+        self.0
+    }
+}
+
+/// Return whether the line without `ln ` starts with `-s`, `-sf` or `-sfn`. The other `ln` lines,
+/// like the ones creating hard links, are not managed.
+pub fn has_symbolic_ln_options(stripped_line: &str) -> bool {
+    stripped_line
+        .split_once(' ')
+        .is_some_and(|(options, _)| matches!(options, "-s" | "-sf" | "-sfn"))
+}
+
+/// Parse `ln -s SRC DEST; \`, `ln -sf SRC DEST; \` or `ln -sfn SRC DEST; \` where `SRC` and
+/// `DEST` are absolute or start with `~/`.
+pub fn parse_stripped_line_with_ln<'a>(
+    stripped_line: &'a str,
+    symlink_map: &mut HashMap<SymlinkDest<'a>, SymlinkSource<'a>>,
+) -> anyhow::Result<CreateSymlink<'a>> {
+    let expected_suffix = "; \\";
+    let Some(options_and_paths) = strip_command_suffix(stripped_line) else {
+        bail!("line with \"ln -s\" but which does not end with {}", quote(expected_suffix));
+    };
+    let mut words = options_and_paths.split(' ');
+    // `str::split` cannot return an empty iterator so `unwrap()` is OK.
+    let options = words.next().unwrap();
+    ensure!(matches!(options, "-s" | "-sf" | "-sfn"), "unsupported ln options: {}", quote(options));
+    let (Some(source_str), Some(dest_str), None) = (words.next(), words.next(), words.next())
+    else {
+        bail!("expected a source and a destination");
+    };
+    for path_str in [source_str, dest_str] {
+        ensure!(
+            path_str.starts_with('/') || path_str.starts_with("~/"),
+            "{} is neither an absolute path nor a path starting with \"~/\"",
+            quote(path_str)
+        );
+    }
+    let source = SymlinkSource::from_str(source_str)?;
+    let dest = SymlinkDest::from_str(dest_str)?;
+    if let Some(previous_source) = symlink_map.insert(dest, source) {
+        bail!(
+            "{} symbolic link already created in a previous line: the source was {}",
+            quote(dest_str),
+            quote(previous_source.as_str())
+        );
+    }
+    Ok(CreateSymlink(dest, source))
+}
+
+pub fn compute_symlink_removal_command<'a>(
+    target_state_symlink_map: &HashMap<SymlinkDest<'a>, SymlinkSource<'a>>,
+    current_state_action: CreateSymlink<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let dest = current_state_action.0;
+    (!target_state_symlink_map.contains_key(&dest)).then(|| {
+        let reason = format!("symbolic link {} removed from target", dest.as_str());
+        command!["rm", dest.as_str()]
+            .unwrap()
+            .with_tilde_expansion()
+            .with_precondition(Precondition::SymlinkOrMissing(dest.as_str().into()))
+            .with_reason(reason)
+    })
+}
+
+pub fn compute_symlink_creation_or_update_command<'a>(
+    current_state_symlink_map: &HashMap<SymlinkDest<'a>, SymlinkSource<'a>>,
+    target_state_action: CreateSymlink<'a>,
+) -> Option<PlannedCommand<'a>> {
+    let CreateSymlink(dest, target_state_source) = target_state_action;
+    let current_state_source = current_state_symlink_map.get(&dest);
+    current_state_source
+        .is_none_or(|current_state_source| current_state_source != &target_state_source)
+        .then(|| {
+            let reason = if let Some(current_state_source) = current_state_source {
+                format!(
+                    "symbolic link {}: {} -> {}",
+                    dest.as_str(),
+                    current_state_source.as_str(),
+                    target_state_source.as_str()
+                )
+            } else {
+                format!("symbolic link {} added to target", dest.as_str())
+            };
+            // `ln -sfn` would replace a real file, so it is also checked.
+            command!["ln", "-sfn", target_state_source.as_str(), dest.as_str()]
+                .unwrap()
+                .with_tilde_expansion()
+                .with_precondition(Precondition::SymlinkOrMissing(dest.as_str().into()))
+                .with_reason(reason)
+        })
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use anyhow::{Context as _, ensure};
+
+use crate::common::{parse_heredoc_delimiter, quote, strip_heredoc_tabs};
+
+/// Parse a `--build-arg NAME=VALUE` option.
+pub fn parse_build_arg(build_arg: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = build_arg.split_once('=').context("'=' is missing")?;
+    ensure!(is_variable_name(name), "invalid variable name {}", quote(name));
+    Ok((name.to_owned(), value.to_owned()))
+}
+
+/// Value of an `ENV` instruction, with its quotes, where only the references to `ARG` variables
+/// are replaced. The other references, like `$HOME` or `$PATH`, are resolved when the command is
+/// executed.
+pub struct EnvVariable {
+    pub line_number: usize,
+    pub name: String,
+    pub value: String,
+}
+
+/// `ENV` variables of a file which apply to a command: the ones declared before the line of the
+/// command, or all of them without line
+pub struct EnvScope<'a> {
+    pub env_variables: &'a [EnvVariable],
+    pub line_number: Option<usize>,
+}
+
+pub struct Expansion {
+    pub file_content: String,
+    pub env_variables: Vec<EnvVariable>,
+}
+
+/// Evaluate the `ARG` defaults and the `ENV` values in file order, then replace `$VAR` and
+/// `${VAR}` with their values in the other lines, so that bumping an `ARG` updates the commands
+/// which use it.
+///
+/// The build args override the `ARG` defaults. Like with `docker build`, the `ARG` variables are
+/// reset at each `FROM` line: the ones declared before the first `FROM` line are only used in the
+/// `FROM` lines, and in the stages which declare them again without value, like `ARG NAME`.
+///
+/// The unknown variables, the single-quoted parts, the comments and the heredoc contents are kept
+/// as is. The result has the same number of lines, so that the line numbers of the errors do not
+/// change.
+pub fn expand_variables(file_content: &str, build_args: &[(String, String)]) -> Expansion {
+    let mut variables = HashMap::new();
+    // The `ARG` variables of the current stage which are not overridden by an `ENV` instruction
+    let mut arg_variables = HashMap::new();
+    // The `ARG` variables declared before the first `FROM` line, or `None` before it
+    let mut global_arg_variables: Option<HashMap<String, String>> = None;
+    let mut env_variables = Vec::new();
+    let mut expanded_content = String::with_capacity(file_content.len());
+    let mut lines = (1..).zip(file_content.lines());
+    while let Some((line_number, line)) = lines.next() {
+        let left_trimmed_line = line.trim_start();
+        if left_trimmed_line.starts_with('#') {
+            expanded_content.push_str(line);
+        } else if let Some(declarations) = left_trimmed_line.strip_prefix("ARG ") {
+            for declaration in split_words(declarations) {
+                let (name, default_value) = match declaration.split_once('=') {
+                    Some((name, default_value)) => (name, Some(default_value)),
+                    None => (declaration, None),
+                };
+                let build_arg_value = build_args
+                    .iter()
+                    .find_map(|(build_arg_name, value)| (build_arg_name == name).then_some(value));
+                let global_value =
+                    global_arg_variables.as_ref().and_then(|global| global.get(name));
+                let value = match (build_arg_value, default_value, global_value) {
+                    (Some(value), _, _) | (None, None, Some(value)) => value.clone(),
+                    (None, Some(default_value), _) => expand(default_value, &variables, true),
+                    (None, None, None) => continue,
+                };
+                variables.insert(name.to_owned(), value.clone());
+                arg_variables.insert(name.to_owned(), value);
+            }
+            expanded_content.push_str(line);
+        } else if let Some(declarations) = left_trimmed_line.strip_prefix("ENV ") {
+            let words = split_words(declarations);
+            let mut declare = |name: &str, value: &str| {
+                let env_value = expand(value, &arg_variables, false);
+                variables.insert(name.to_owned(), expand(value, &variables, true));
+                arg_variables.remove(name);
+                env_variables.push(EnvVariable {
+                    line_number,
+                    name: name.to_owned(),
+                    value: env_value,
+                });
+            };
+            if words.first().is_some_and(|word| word.contains('=')) {
+                for declaration in words {
+                    if let Some((name, value)) = declaration.split_once('=') {
+                        declare(name, value);
+                    }
+                }
+            } else if let Some((name, value)) = declarations.trim().split_once(' ') {
+                // Legacy syntax: `ENV NAME VALUE`
+                declare(name, value.trim());
+            }
+            expanded_content.push_str(line);
+        } else if left_trimmed_line.starts_with("FROM ") {
+            let global_arg_variables =
+                global_arg_variables.get_or_insert_with(|| arg_variables.clone());
+            expanded_content.push_str(&expand(line, global_arg_variables, false));
+            variables.retain(|name, _| !arg_variables.contains_key(name));
+            arg_variables.clear();
+        } else {
+            expanded_content.push_str(&expand(line, &variables, false));
+            if let Some(stripped_line) = left_trimmed_line.strip_prefix("COPY <<") {
+                // The heredoc lines are file contents.
+                let delimiter_str = stripped_line.split(' ').next().unwrap_or_default();
+                let (delimiter, strip_tabs) = parse_heredoc_delimiter(delimiter_str);
+                for (_, heredoc_line) in lines.by_ref() {
+                    expanded_content.push('\n');
+                    expanded_content.push_str(heredoc_line);
+                    if strip_heredoc_tabs(heredoc_line, strip_tabs) == delimiter {
+                        break;
+                    }
+                }
+            }
+        }
+        expanded_content.push('\n');
+    }
+    if !file_content.ends_with('\n') {
+        expanded_content.pop();
+    }
+    Expansion { file_content: expanded_content, env_variables }
+}
+
+/// Return the `ENV` declarations of the scopes which apply to a command of the target state, in
+/// order, with their unresolved values. With `ignore_home`, the `HOME` declarations are dropped,
+/// so that `$HOME` is the one of the user.
+///
+/// For a command of an included file, the scopes are the including files up to the include
+/// directives, then the included file up to the line of the command. A command without line, like
+/// a removal, gets all the `ENV` variables of the target state file.
+pub fn env_declarations(env_scopes: &[EnvScope], ignore_home: bool) -> Vec<(String, String)> {
+    env_scopes
+        .iter()
+        .flat_map(|&EnvScope { env_variables, line_number }| {
+            env_variables.iter().take_while(move |env_variable| {
+                line_number.is_none_or(|number| env_variable.line_number < number)
+            })
+        })
+        .filter(|env_variable| !ignore_home || env_variable.name != "HOME")
+        .map(|EnvVariable { name, value, .. }| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Return the environment variables given to a command: the `ENV` declarations, in order, with
+/// the references to the variables of this process and of the previous declarations resolved.
+pub fn child_environment(env_declarations: &[(String, String)]) -> Vec<(String, String)> {
+    let mut variables: HashMap<_, _> = std::env::vars().collect();
+    let mut environment: Vec<(String, String)> = Vec::new();
+    for (name, value) in env_declarations {
+        let value = expand(value, &variables, true);
+        variables.insert(name.clone(), value.clone());
+        if let Some((_, previous_value)) =
+            environment.iter_mut().find(|(previous_name, _)| previous_name == name)
+        {
+            *previous_value = value;
+        } else {
+            environment.push((name.clone(), value));
+        }
+    }
+    environment
+}
+
+/// Convert an `ENV` value to a double-quoted word of a POSIX shell, where the references to the
+/// variables are resolved when the shell runs, like `child_environment` does: `$HOME` becomes
+/// `${HOME-\$HOME}`.
+pub fn to_shell_word(value: &str) -> String {
+    let mut word = String::with_capacity(value.len() + 2);
+    word.push('"');
+    let push_escaped = |word: &mut String, text: &str| {
+        for character in text.chars() {
+            if matches!(character, '"' | '\\' | '`' | '$') {
+                word.push('\\');
+            }
+            word.push(character);
+        }
+    };
+    let mut quote_char = None;
+    let mut rest = value;
+    while let Some(character) = rest.chars().next() {
+        let mut consumed_len = character.len_utf8();
+        match (quote_char, character) {
+            (None, '"' | '\'') | (Some('"'), '"') | (Some('\''), '\'') => {
+                quote_char = quote_char.is_none().then_some(character);
+            }
+            (None | Some('"'), '\\') => {
+                // The escaped character is kept as is.
+                consumed_len += rest[1..].chars().next().map_or(0, char::len_utf8);
+                push_escaped(&mut word, &rest[..consumed_len]);
+            }
+            (None | Some('"'), '$') => match variable_reference(rest) {
+                Some((name, reference_len)) => {
+                    // Like `expand`, an unknown variable is kept as is.
+                    consumed_len = reference_len;
+                    word.push_str("${");
+                    word.push_str(name);
+                    word.push('-');
+                    word.push_str(&rest[..reference_len].replace('$', "\\$").replace('}', "\\}"));
+                    word.push('}');
+                }
+                None => word.push_str("\\$"),
+            },
+            _ => push_escaped(&mut word, &rest[..consumed_len]),
+        }
+        rest = &rest[consumed_len..];
+    }
+    word.push('"');
+    word
+}
+
+/// Return the `HOME` of the environment of a command, or the one of this process.
+pub fn home_dir(environment: &[(String, String)]) -> Option<String> {
+    environment
+        .iter()
+        .find_map(|(name, value)| (name == "HOME").then(|| value.clone()))
+        .or_else(|| std::env::var("HOME").ok())
+}
+
+fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|character: char| character.is_ascii_alphabetic() || character == '_')
+        && name.chars().all(|character| character.is_ascii_alphanumeric() || character == '_')
+}
+
+/// Split on the spaces which are not quoted.
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut quote_char = None;
+    let mut word_start_index = None;
+    for (index, character) in text.char_indices() {
+        match (quote_char, character) {
+            (None, ' ') => {
+                if let Some(start_index) = word_start_index.take() {
+                    words.push(&text[start_index..index]);
+                }
+                continue;
+            }
+            (None, '"' | '\'') => quote_char = Some(character),
+            (Some(opening_quote_char), _) if opening_quote_char == character => quote_char = None,
+            _ => {}
+        }
+        word_start_index.get_or_insert(index);
+    }
+    if let Some(start_index) = word_start_index {
+        words.push(&text[start_index..]);
+    }
+    words
+}
+
+/// Replace the references to known variables, except in single quotes, like a shell would do.
+fn expand(text: &str, variables: &HashMap<String, String>, remove_quotes: bool) -> String {
+    let mut expanded_text = String::with_capacity(text.len());
+    let mut quote_char = None;
+    let mut rest = text;
+    while let Some(character) = rest.chars().next() {
+        let mut consumed_len = character.len_utf8();
+        match (quote_char, character) {
+            (None, '"' | '\'') | (Some('"'), '"') | (Some('\''), '\'') => {
+                quote_char = quote_char.is_none().then_some(character);
+                if !remove_quotes {
+                    expanded_text.push(character);
+                }
+            }
+            (None | Some('"'), '\\') => {
+                // The escaped character is kept as is.
+                let escaped_len = rest[1..].chars().next().map_or(0, char::len_utf8);
+                consumed_len += escaped_len;
+                expanded_text.push_str(&rest[..consumed_len]);
+            }
+            (None | Some('"'), '$') => {
+                let reference = variable_reference(rest);
+                let value = reference.and_then(|(name, _)| variables.get(name));
+                if let (Some((_, reference_len)), Some(value)) = (reference, value) {
+                    consumed_len = reference_len;
+                    expanded_text.push_str(value);
+                } else {
+                    expanded_text.push('$');
+                }
+            }
+            _ => expanded_text.push(character),
+        }
+        rest = &rest[consumed_len..];
+    }
+    expanded_text
+}
+
+/// Return the name and the length of `$VAR` or `${VAR}` at the start of the text.
+fn variable_reference(text: &str) -> Option<(&str, usize)> {
+    let rest = text.strip_prefix('$')?;
+    if let Some(braced_rest) = rest.strip_prefix('{') {
+        let (name, _) = braced_rest.split_once('}')?;
+        is_variable_name(name).then_some((name, name.len() + "${}".len()))
+    } else {
+        let name_len = rest
+            .find(|character: char| !character.is_ascii_alphanumeric() && character != '_')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_len];
+        is_variable_name(name).then_some((name, name.len() + "$".len()))
+    }
+}
@@ -1,5 +1,75 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+use std::process;
+
 use anyhow::{Context as _, ensure};
 
+const CHANGES_PLANNED_EXIT_CODE: i32 = 3;
+
+/// `sync_install` built and run by Cargo.
+fn sync_install() -> process::Command {
+    let mut command = process::Command::new("cargo");
+    command.args(["run", "-q", "--"]);
+    command
+}
+
+/// Temporary directory of a test, removed when dropped, even if the test fails.
+struct Fixture {
+    dir_path: PathBuf,
+}
+
+impl Fixture {
+    /// The directory name has the test name and the process id, so that the tests do not share
+    /// their files, even with another run of the tests.
+    fn new(test_name: &str) -> anyhow::Result<Self> {
+        let dir_path =
+            std::env::temp_dir().join(format!("sync_install_{test_name}_{}", process::id()));
+        fs::create_dir_all(dir_path.join("bin"))
+            .context("failed to create the temporary directory")?;
+        Ok(Self { dir_path })
+    }
+
+    fn path(&self, relative_path: &str) -> PathBuf {
+        self.dir_path.join(relative_path)
+    }
+
+    /// Directory of the fake executables.
+    fn bin_path(&self) -> PathBuf {
+        self.path("bin")
+    }
+
+    fn write(&self, relative_path: &str, content: impl AsRef<[u8]>) -> anyhow::Result<PathBuf> {
+        let path = self.path(relative_path);
+        fs::write(&path, content).with_context(|| format!("failed to write {relative_path}"))?;
+        Ok(path)
+    }
+
+    fn write_executable(&self, file_name: &str, content: &str) -> anyhow::Result<()> {
+        let path = self.bin_path().join(file_name);
+        fs::write(&path, content).with_context(|| format!("failed to write {file_name}"))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .context("failed to set the permissions")
+    }
+
+    /// `PATH` which begins with the directory of the fake executables. A fake `cargo` must be
+    /// given with `ENV PATH` instead, else it would replace the one of `cargo run`.
+    fn path_with_fake_executables(&self) -> anyhow::Result<OsString> {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        std::env::join_paths(std::iter::once(self.bin_path()).chain(std::env::split_paths(&path)))
+            .context("failed to build PATH")
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        // A leftover temporary directory must not hide the result of the test.
+        let _ignored_error = fs::remove_dir_all(&self.dir_path);
+    }
+}
+
 const EXPECTED_OUTPUT: &str =
     "This is a dry run. Add the --go option to execute the below command(s).
 ---> [cargo uninstall fsays]
@@ -9,17 +79,837 @@ const EXPECTED_OUTPUT: &str =
 
 #[test]
 fn example_from_the_cli_help_and_the_readme() -> anyhow::Result<()> {
-    let output = std::process::Command::new("cargo")
-        .arg("run")
-        .arg("-q")
-        .arg("--")
+    let output = sync_install()
         .arg("dockerfiles/current_state_from_readme")
         .arg("dockerfiles/target_state_from_readme")
         .output()
         .context("failed to execute process")?;
     let status = output.status;
-    ensure!(status.success(), "error status: {status}");
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
     let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
     assert_eq!(stdout, EXPECTED_OUTPUT);
     Ok(())
 }
+
+const EXPECTED_EXPLAIN_OUTPUT: &str =
+    "This is a dry run. Add the --go option to execute the below command(s).
+---> [cargo uninstall fsays]
+     reason: crate fsays removed from target
+     \"dockerfiles/current_state_from_readme\":5: cargo install fsays --version 0.3.0 --locked; \\
+---> [cargo install cargo-cache --version 0.8.3 --force]
+     reason: crate cargo-cache: [cargo install cargo-cache --version 0.8.3 --locked] -> [cargo install cargo-cache --version 0.8.3]
+     \"dockerfiles/current_state_from_readme\":3: cargo install cargo-cache --version 0.8.3 --locked; \\
+     \"dockerfiles/target_state_from_readme\":3: cargo install cargo-cache --version 0.8.3; \\
+     tokens: -\"--locked\"
+---> [cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag v0.68.0 --locked]
+     reason: crate pixi added to target
+     \"dockerfiles/target_state_from_readme\":5: cargo install pixi --git https://github.com/prefix-dev/pixi.git --tag v0.68.0 --locked; \\
+";
+
+#[test]
+fn explain_the_example_from_the_readme() -> anyhow::Result<()> {
+    let output = sync_install()
+        .arg("dockerfiles/current_state_from_readme")
+        .arg("dockerfiles/target_state_from_readme")
+        .arg("--explain")
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    assert_eq!(stdout, EXPECTED_EXPLAIN_OUTPUT);
+    Ok(())
+}
+
+#[test]
+fn exit_codes() -> anyhow::Result<()> {
+    let fixture = Fixture::new("exit_codes")?;
+    let invalid_file_path =
+        fixture.write("invalid", "RUN cargo install fsays --version 0.3.0 --locked\n")?;
+    let script_path = fixture.path("out.sh");
+    let exit_code =
+        |current_state_file_path: &Path, target_state_file_path: &Path, args: &[&Path]| {
+            sync_install()
+                .arg(current_state_file_path)
+                .arg(target_state_file_path)
+                .args(args)
+                .output()
+                .context("failed to execute process")
+                .map(|output| output.status.code())
+        };
+    let current_file_path = Path::new("dockerfiles/current_state_from_readme");
+    let readme_file_path = Path::new("dockerfiles/target_state_from_readme");
+    let emit_script_args = [Path::new("--emit-script"), &script_path];
+    assert_eq!(exit_code(readme_file_path, readme_file_path, &[])?, Some(0));
+    assert_eq!(exit_code(readme_file_path, &invalid_file_path, &[])?, Some(4));
+    assert_eq!(exit_code(readme_file_path, &fixture.path("missing"), &[])?, Some(5));
+    assert_eq!(exit_code(readme_file_path, readme_file_path, &emit_script_args)?, Some(0));
+    assert_eq!(
+        exit_code(current_file_path, readme_file_path, &emit_script_args)?,
+        Some(CHANGES_PLANNED_EXIT_CODE)
+    );
+    Ok(())
+}
+
+#[test]
+fn snap_commands_with_fake_executables() -> anyhow::Result<()> {
+    let fixture = Fixture::new("snap_commands_with_fake_executables")?;
+    let log_path = fixture.path("log.txt");
+    // `sudo` resets `PATH` so it is also faked.
+    fixture.write_executable("sudo", "#!/bin/sh\nexec \"$@\"\n")?;
+    fixture.write_executable(
+        "snap",
+        &format!("#!/bin/sh\necho \"snap $*\" >> '{}'\n", log_path.display()),
+    )?;
+    fixture.write("current", "RUN set -eux; \\\n    snap install vlc; \\\n    true\n")?;
+    fixture.write(
+        "target",
+        "RUN set -eux; \\\n    snap install --classic --channel=edge codium; \\\n    true\n",
+    )?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--go")
+        .env("PATH", fixture.path_with_fake_executables()?)
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.success(), "error status: {status}");
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    assert_eq!(log, "snap remove vlc\nsnap install --classic --channel=edge codium\n");
+    Ok(())
+}
+
+#[test]
+fn env_instructions_given_to_the_commands() -> anyhow::Result<()> {
+    let fixture = Fixture::new("env_instructions_given_to_the_commands")?;
+    let log_path = fixture.path("log.txt");
+    fixture.write_executable(
+        "pixi",
+        &format!("#!/bin/sh\necho \"$HOME $PIXI_HOME\" >> '{}'\n", log_path.display()),
+    )?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        format!(
+            "ENV HOME=\"/nonexistent_home\"\n\
+            ENV PATH=\"{}:$PATH\" PIXI_HOME=\"$HOME/.pixi\"\n\
+            RUN set -eux; \\\n    pixi global install git=2.55.0; \\\n    true\n\
+            ENV GIT_VERSION=2.55.0\n",
+            fixture.bin_path().display()
+        ),
+    )?;
+    let run = |ignore_env_home: bool| {
+        sync_install()
+            .arg(fixture.path("current"))
+            .arg(fixture.path("target"))
+            .arg("--go")
+            .args(ignore_env_home.then_some("--ignore-env-home"))
+            .env("HOME", "/home/alice")
+            .output()
+            .context("failed to execute process")
+    };
+    let status = run(false)?.status;
+    ensure!(status.success(), "error status: {status}");
+    let status = run(true)?.status;
+    ensure!(status.success(), "error status: {status}");
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    assert_eq!(log, "/nonexistent_home /nonexistent_home/.pixi\n/home/alice /home/alice/.pixi\n");
+    Ok(())
+}
+
+#[test]
+fn env_instructions_of_the_including_and_included_files() -> anyhow::Result<()> {
+    let fixture = Fixture::new("env_instructions_of_the_including_and_included_files")?;
+    let log_path = fixture.path("log.txt");
+    fixture.write_executable(
+        "pixi",
+        &format!("#!/bin/sh\necho \"$HOME $GIT_VERSION\" >> '{}'\n", log_path.display()),
+    )?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        format!(
+            "ENV PATH=\"{}:$PATH\"\n\
+            ENV HOME=\"/nonexistent_home\"\n\
+            # sync_install: include included\n\
+            ENV GIT_VERSION=2.56.0\n",
+            fixture.bin_path().display()
+        ),
+    )?;
+    fixture.write(
+        "included",
+        "ENV GIT_VERSION=2.55.0\n\
+        RUN set -eux; \\\n    pixi global install git=2.55.0; \\\n    true\n\
+        ENV HOME=\"/late_home\"\n",
+    )?;
+    let status = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--go")
+        .output()
+        .context("failed to execute process")?
+        .status;
+    ensure!(status.success(), "error status: {status}");
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    assert_eq!(log, "/nonexistent_home 2.55.0\n");
+    Ok(())
+}
+
+#[test]
+fn download_with_a_file_url() -> anyhow::Result<()> {
+    const SHA256: &str = "8d0729d3a832724cf82652aedd31080f66b19e001a244a18f750879283697f21";
+    let fixture = Fixture::new("download_with_a_file_url")?;
+    fixture.write("gitalias.txt", "[alias]\n")?;
+    fixture.write("current", "")?;
+    let path = fixture.dir_path.display();
+    fixture.write(
+        "target",
+        format!(
+            "RUN set -eux; \\\n\
+            \x20   curl -fsSL file://{path}/gitalias.txt -o {path}/.gitalias; \\\n\
+            \x20   echo \"{SHA256}  {path}/.gitalias\" | sha256sum -c; \\\n\
+            \x20   true\n"
+        ),
+    )?;
+    let status = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--go")
+        .output()
+        .context("failed to execute process")?
+        .status;
+    ensure!(status.success(), "error status: {status}");
+    let downloaded_content = fs::read_to_string(fixture.path(".gitalias"))
+        .context("failed to read the downloaded file")?;
+    assert_eq!(downloaded_content, "[alias]\n");
+    Ok(())
+}
+
+#[test]
+fn symlink_removal_refused_for_a_real_file() -> anyhow::Result<()> {
+    let fixture = Fixture::new("symlink_removal_refused_for_a_real_file")?;
+    let dest_path = fixture.write("dest", "real file")?;
+    fixture.write(
+        "current",
+        format!("RUN set -eux; \\\n    ln -sfn /tmp {}; \\\n    true\n", dest_path.display()),
+    )?;
+    fixture.write("target", "")?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--go")
+        .output()
+        .context("failed to execute process")?;
+    ensure!(!output.status.success(), "unexpected success");
+    let stderr = String::from_utf8(output.stderr).context("non-UTF8 command output")?;
+    ensure!(stderr.contains("exists and is not a symbolic link"), "unexpected stderr: {stderr}");
+    let content = fs::read_to_string(&dest_path).context("failed to read the real file")?;
+    assert_eq!(content, "real file");
+    Ok(())
+}
+
+#[test]
+fn interactive_mode() -> anyhow::Result<()> {
+    let fixture = Fixture::new("interactive_mode")?;
+    fixture.write("current", "")?;
+    let dir = fixture.dir_path.display();
+    fixture.write(
+        "target",
+        format!(
+            "RUN set -eux; \\\n    ln -s /tmp {dir}/skipped; \\\n    ln -s /tmp {dir}/created; \\\n    \
+            true\n"
+        ),
+    )?;
+    let mut child = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--interactive")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .context("failed to execute process")?;
+    // `stdin` is piped so `unwrap()` is OK.
+    child.stdin.take().unwrap().write_all(b"n\ny\n").context("failed to write to stdin")?;
+    let output = child.wait_with_output().context("failed to wait for the process")?;
+    let status = output.status;
+    ensure!(status.success(), "error status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    ensure!(!fixture.path("skipped").exists(), "the skipped command was executed");
+    ensure!(fixture.path("created").is_symlink(), "the confirmed command was not executed");
+    ensure!(
+        stdout.ends_with(&format!(
+            "Executed command(s): 1. Skipped command(s): 1. So the target state is only partially \
+            applied. The skipped command(s) are:\n\
+            ---> [ln -sfn /tmp {dir}/skipped] (symbolic link {dir}/skipped added to target)\n"
+        )),
+        "unexpected stdout: {stdout}"
+    );
+    Ok(())
+}
+
+#[test]
+fn keep_going_after_a_failure() -> anyhow::Result<()> {
+    let fixture = Fixture::new("keep_going_after_a_failure")?;
+    let log_path = fixture.path("log.txt");
+    fixture.write_executable("sudo", "#!/bin/sh\nexec \"$@\"\n")?;
+    fixture.write_executable(
+        "snap",
+        &format!(
+            "#!/bin/sh\ncase \"$*\" in *broken*) echo \"error: boom\" >&2; exit 3;; esac\n\
+            echo \"snap $*\" >> '{}'\n",
+            log_path.display()
+        ),
+    )?;
+    fixture.write_executable("pixi", "#!/bin/sh\necho \"error: offline\" >&2\nexit 1\n")?;
+    fixture.write_executable(
+        "git",
+        &format!("#!/bin/sh\necho \"git $*\" >> '{}'\n", log_path.display()),
+    )?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        "RUN set -eux; \\\n\
+        \x20   snap install broken; \\\n\
+        \x20   pixi global install git=2.55.0; \\\n\
+        \x20   git config set --global init.defaultBranch main; \\\n\
+        \x20   snap install vlc; \\\n\
+        \x20   true\n",
+    )?;
+    for job_count in ["1", "2"] {
+        let output = sync_install()
+            .arg(fixture.path("current"))
+            .arg(fixture.path("target"))
+            .arg("--go")
+            .arg("--keep-going")
+            .args(["--jobs", job_count])
+            .env("PATH", fixture.path_with_fake_executables()?)
+            .output()
+            .context("failed to execute process")?;
+        let status = output.status;
+        ensure!(status.code() == Some(1), "unexpected status: {status}");
+        let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+        assert!(
+            stdout.ends_with(
+                "Succeeded command(s): 1. Failed command(s): 2:\n\
+            STATUS         | COMMAND AND STDERR TAIL\n\
+            exit status: 3 | [sudo snap install broken]\n               |     error: boom\n\
+            exit status: 1 | [pixi global install 'git=2.55.0']\n               |     error: offline\n\
+            Skipped command(s), which depend on a failed one: 1:\n\
+            ---> [git config set --global init.defaultBranch main]\n"
+            ),
+            "unexpected output: {stdout}"
+        );
+    }
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    assert_eq!(log, "snap install vlc\nsnap install vlc\n");
+    Ok(())
+}
+
+#[test]
+fn parallel_jobs_with_prefixed_output() -> anyhow::Result<()> {
+    let fixture = Fixture::new("parallel_jobs_with_prefixed_output")?;
+    let log_path = fixture.path("log.txt");
+    fixture.write_executable("sudo", "#!/bin/sh\nexec \"$@\"\n")?;
+    // The first snap is the slowest, but the snap commands share a lock so they keep their order.
+    fixture.write_executable(
+        "snap",
+        &format!(
+            "#!/bin/sh\n[ \"$2\" = first ] && sleep 1\necho \"snap $*\" >> '{}'\necho \"$2 done\"\n",
+            log_path.display()
+        ),
+    )?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        format!(
+            "RUN set -eux; \\\n    snap install first; \\\n    snap install second; \\\n    \
+            ln -s /tmp {}/link; \\\n    true\n",
+            fixture.dir_path.display()
+        ),
+    )?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--go")
+        .arg("--jobs")
+        .arg("2")
+        .env("PATH", fixture.path_with_fake_executables()?)
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.success(), "error status: {status}");
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    assert_eq!(log, "snap install first\nsnap install second\n");
+    assert!(fixture.path("link").is_symlink());
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines.contains(&"[1] ---> [sudo snap install first]"));
+    assert!(lines.contains(&"[1] first done"));
+    assert!(lines.contains(&"[2] second done"));
+    // The symbolic link does not wait for the slow snap.
+    let position = |prefix: &str| lines.iter().position(|line| line.starts_with(prefix)).unwrap();
+    assert!(position("[3] ---> [ln -sfn /tmp ") < position("[1] first done"));
+    Ok(())
+}
+
+#[test]
+fn parallel_jobs_forward_short_lines() -> anyhow::Result<()> {
+    let fixture = Fixture::new("parallel_jobs_forward_short_lines")?;
+    fixture.write_executable("sudo", "#!/bin/sh\nexec \"$@\"\n")?;
+    // The short lines are followed by a long output, which needs the pipe to still be read.
+    fixture.write_executable("snap", "#!/bin/sh\necho\necho ok\nseq 100000\necho \"$2 done\"\n")?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        "RUN set -eux; \\\n    snap install first; \\\n    snap install second; \\\n    true\n",
+    )?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--go")
+        .arg("--jobs")
+        .arg("2")
+        .env("PATH", fixture.path_with_fake_executables()?)
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.success(), "error status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    let lines: Vec<_> = stdout.lines().collect();
+    for line in ["[1] ", "[1] ok", "[1] first done", "[2] ", "[2] ok", "[2] second done"] {
+        assert!(lines.contains(&line), "missing line {line:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn emitted_script_checks_the_preconditions() -> anyhow::Result<()> {
+    let fixture = Fixture::new("emitted_script_checks_the_preconditions")?;
+    let real_file_path = fixture.write("real", "real file")?;
+    fixture.write(
+        "current",
+        format!("RUN set -eux; \\\n    ln -s /tmp {}; \\\n    true\n", real_file_path.display()),
+    )?;
+    fixture.write(
+        "target",
+        format!(
+            "RUN set -eux; \\\n    ln -s /tmp {}/link; \\\n    true\n",
+            fixture.dir_path.display()
+        ),
+    )?;
+    let script_path = fixture.path("out.sh");
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--emit-script")
+        .arg(&script_path)
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    ensure!(output.stdout.is_empty(), "unexpected stdout");
+    let script_output =
+        process::Command::new(&script_path).output().context("failed to execute the script")?;
+    ensure!(!script_output.status.success(), "the script did not fail");
+    let stderr = String::from_utf8(script_output.stderr).context("non-UTF8 script output")?;
+    assert!(stderr.ends_with("/real\" exists and is not a symbolic link\n"));
+    let real_file_content = fs::read_to_string(&real_file_path).context("failed to read")?;
+    assert_eq!(real_file_content, "real file");
+    assert!(!fixture.path("link").is_symlink());
+    Ok(())
+}
+
+#[test]
+fn emitted_script_gives_the_env_instructions() -> anyhow::Result<()> {
+    let fixture = Fixture::new("emitted_script_gives_the_env_instructions")?;
+    let log_path = fixture.path("log.txt");
+    fixture.write_executable(
+        "pixi",
+        &format!("#!/bin/sh\necho \"$HOME|$PIXI_HOME|$MESSAGE\" >> '{}'\n", log_path.display()),
+    )?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        format!(
+            "ENV PATH=\"{}:$PATH\" PIXI_HOME=\"$HOME/.pixi\"\n\
+            ENV MESSAGE=\"it's 1 & 2\"\n\
+            RUN set -eux; \\\n    pixi global install git=2.55.0; \\\n    ln -s /tmp ~/link; \\\n    \
+            true\n",
+            fixture.bin_path().display()
+        ),
+    )?;
+    let script_path = fixture.path("out.sh");
+    let status = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("target"))
+        .arg("--emit-script")
+        .arg(&script_path)
+        .output()
+        .context("failed to execute process")?
+        .status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    let script = fs::read_to_string(&script_path).context("failed to read the script")?;
+    ensure!(script.starts_with("#!/bin/sh\n"), "missing #!/bin/sh");
+    ensure!(script.contains(" ln -sfn /tmp ~/link\n"), "the ~ is expanded");
+    // The script runs with another home directory than the one of the generation.
+    let home = fixture.path("home");
+    fs::create_dir(&home).context("failed to create the home directory")?;
+    let status = process::Command::new(&script_path)
+        .env("HOME", &home)
+        .output()
+        .context("failed to execute the script")?
+        .status;
+    ensure!(status.success(), "script error status: {status}");
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    let home = home.display();
+    assert_eq!(log, format!("{home}|{home}/.pixi|it's 1 & 2\n"));
+    assert!(fixture.path("home/link").is_symlink());
+    Ok(())
+}
+
+#[test]
+fn lint_reports_all_the_problems() -> anyhow::Result<()> {
+    let fixture = Fixture::new("lint_reports_all_the_problems")?;
+    let file_path = fixture.write(
+        "Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   cargo install fsays --version 0.3.0; \\\n\
+        \x20   cargo install ; \\\n\
+        \x20\t  pixi global install git=*; \\\n\
+        \x20   pixi global install git; \\\n\
+        \x20   # sync_install: ignore\n\
+        \x20   pixi global install ripgrep; \\\n\
+        \x20   cargo install cargo-cache --locked\n",
+    )?;
+    let output =
+        sync_install().arg("lint").arg(&file_path).output().context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.code() == Some(4), "unexpected status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    let path = format!("\"{}\"", file_path.display());
+    assert_eq!(
+        stdout,
+        format!(
+            "{path}:2:5: warning: \"fsays\" crate installed without --locked\n\
+            {path}:3:5: error: empty crate name (cargo install handler)\n\
+            {path}:4:2: warning: indentation mixes tabs and spaces\n\
+            {path}:4:25: warning: unpinned pixi recipe \"git=*\"\n\
+            {path}:5:5: error: '=' is missing (pixi global install handler)\n\
+            {path}:7:25: warning: unpinned pixi recipe \"ripgrep\"\n\
+            {path}:8:5: error: line with \"cargo install \" but which does not end with \"; \\\" \
+            (cargo install handler)\n"
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn lint_accepts_the_clean_lines() -> anyhow::Result<()> {
+    let fixture = Fixture::new("lint_accepts_the_clean_lines")?;
+    let file_path = fixture.write(
+        "Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   cargo install cargo-cache --version=0.8.3 --locked; \\\n\
+        \x20   pixi global install git=2.55.0; \\\n\
+        \x20   true\n",
+    )?;
+    for file_path in [&file_path, Path::new("dockerfiles/tested_example_1")] {
+        let output = sync_install()
+            .arg("lint")
+            .arg(file_path)
+            .output()
+            .context("failed to execute process")?;
+        let status = output.status;
+        ensure!(status.success(), "error status: {status}");
+        let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+        assert_eq!(stdout, "");
+    }
+    Ok(())
+}
+
+#[test]
+fn fmt_rewrites_the_managed_lines_canonically() -> anyhow::Result<()> {
+    let fixture = Fixture::new("fmt_rewrites_the_managed_lines_canonically")?;
+    let file_path = fixture.write(
+        "Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   cargo install fsays --locked --version 0.3.0; \\\n\
+        \x20   git config set --global user.name 'John Smith'; \\\n\
+        \x20   git config set --global init.defaultBranch main; \\\n\
+        \x20   cargo cache -r all\n",
+    )?;
+    let run_fmt = || {
+        let status = sync_install()
+            .arg("fmt")
+            .arg(&file_path)
+            .status()
+            .context("failed to execute process")?;
+        ensure!(status.success(), "error status: {status}");
+        fs::read_to_string(&file_path).context("failed to read")
+    };
+    let first_content = run_fmt()?;
+    assert_eq!(
+        first_content,
+        "RUN set -eux; \\\n\
+        \x20   cargo install fsays --version 0.3.0 --locked;    \\\n\
+        \x20   git config set --global init.defaultBranch main; \\\n\
+        \x20   git config set --global user.name 'John Smith';  \\\n\
+        \x20   cargo cache -r all\n"
+    );
+    assert_eq!(run_fmt()?, first_content);
+    Ok(())
+}
+
+#[test]
+fn included_files_are_merged() -> anyhow::Result<()> {
+    let fixture = Fixture::new("included_files_are_merged")?;
+    fs::create_dir_all(fixture.path("team")).context("failed to create the team directory")?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "target",
+        "RUN set -eux; \\\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   true\n\
+        # sync_install: include team/team.Dockerfile\n\
+        RUN set -eux; \\\n\
+        \x20   pixi global install git=2.55.0; \\\n\
+        \x20   true\n",
+    )?;
+    fixture.write(
+        "team/team.Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   cargo install cargo-cache --version 0.8.3 --locked; \\\n\
+        \x20   true\n",
+    )?;
+    fixture.write("duplicate", "# sync_install: include team/duplicate.Dockerfile\n")?;
+    fixture.write(
+        "team/duplicate.Dockerfile",
+        "# sync_install: include team.Dockerfile\n\
+        RUN set -eux; \\\n\
+        \x20   cargo install cargo-cache --version 0.8.2 --locked; \\\n\
+        \x20   true\n",
+    )?;
+    fixture.write("team/cycle.Dockerfile", "# sync_install: include ../cycle\n")?;
+    fixture.write("cycle", "# sync_install: include team/cycle.Dockerfile\n")?;
+    fixture.write("missing", "# sync_install: include team/missing.Dockerfile\n")?;
+    let run = |target_state_file_name: &str| {
+        sync_install()
+            .arg(fixture.path("current"))
+            .arg(fixture.path(target_state_file_name))
+            .arg("--explain")
+            .output()
+            .context("failed to execute process")
+    };
+    let target_output = run("target")?;
+    let status = target_output.status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    let stdout = String::from_utf8(target_output.stdout).context("non-UTF8 command output")?;
+    let path = fixture.dir_path.display();
+    assert_eq!(
+        stdout,
+        format!(
+            "This is a dry run. Add the --go option to execute the below command(s).\n\
+            ---> [cargo install fsays --version 0.3.0 --locked]\n\
+            \x20    reason: crate fsays added to target\n\
+            \x20    \"{path}/target\":2: cargo install fsays --version 0.3.0 --locked; \\\n\
+            ---> [cargo install cargo-cache --version 0.8.3 --locked]\n\
+            \x20    reason: crate cargo-cache added to target\n\
+            \x20    \"{path}/team/team.Dockerfile\":2: cargo install cargo-cache --version 0.8.3 --locked; \\\n\
+            ---> [pixi global install 'git=2.55.0']\n\
+            \x20    reason: recipe git added to target\n\
+            \x20    \"{path}/target\":6: pixi global install git=2.55.0; \\\n"
+        )
+    );
+    let duplicate_output = run("duplicate")?;
+    ensure!(duplicate_output.status.code() == Some(4), "unexpected duplicate status");
+    let stderr = String::from_utf8(duplicate_output.stderr).context("non-UTF8 command output")?;
+    assert!(stderr.contains(&format!(
+        "[cargo install cargo-cache --version 0.8.3 --locked; \\] at \
+        \"{path}/team/team.Dockerfile\":2 already declared at \"{path}/team/duplicate.Dockerfile\":3: \
+        [cargo install cargo-cache --version 0.8.2 --locked; \\]"
+    )));
+    let cycle_output = run("cycle")?;
+    ensure!(cycle_output.status.code() == Some(4), "unexpected cycle status");
+    let stderr = String::from_utf8(cycle_output.stderr).context("non-UTF8 command output")?;
+    assert!(stderr.contains(&format!(
+        "include cycle: \"{path}/cycle\" -> \"{path}/team/cycle.Dockerfile\" -> \"{path}/cycle\""
+    )));
+    let missing_output = run("missing")?;
+    ensure!(missing_output.status.code() == Some(5), "unexpected missing status");
+    Ok(())
+}
+
+#[test]
+fn overlays_replace_the_entries_of_the_target_state() -> anyhow::Result<()> {
+    let fixture = Fixture::new("overlays_replace_the_entries_of_the_target_state")?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "team.Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   cargo install fsays --version 0.2.0 --locked; \\\n\
+        \x20   git config set --global user.name 'Team'; \\\n\
+        \x20   cargo install cargo-cache --version 0.8.3 --locked; \\\n\
+        \x20   true\n",
+    )?;
+    fixture.write(
+        "me.Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   git config set --global user.name 'Me'; \\\n\
+        \x20   cargo install fsays --version 0.3.0 --locked; \\\n\
+        \x20   true\n",
+    )?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("team.Dockerfile"))
+        .arg("--overlay")
+        .arg(fixture.path("me.Dockerfile"))
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    let path = fixture.dir_path.display();
+    assert_eq!(
+        stdout,
+        format!(
+            "This is a dry run. Add the --go option to execute the below command(s).\n\
+            ---> [cargo install fsays --version 0.3.0 --locked]\n\
+            \x20    layer: \"{path}/me.Dockerfile\"\n\
+            ---> [git config set --global user.name Me]\n\
+            \x20    layer: \"{path}/me.Dockerfile\"\n\
+            ---> [cargo install cargo-cache --version 0.8.3 --locked]\n\
+            \x20    layer: \"{path}/team.Dockerfile\"\n"
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn overlay_replacing_a_pinned_flatpak_application() -> anyhow::Result<()> {
+    let fixture = Fixture::new("overlay_replacing_a_pinned_flatpak_application")?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "team.Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   flatpak install -y flathub org.gimp.GIMP; \\\n\
+        \x20   flatpak update -y --commit=abc org.gimp.GIMP; \\\n\
+        \x20   true\n",
+    )?;
+    fixture.write(
+        "me.Dockerfile",
+        "RUN set -eux; \\\n    flatpak install -y --user flathub org.gimp.GIMP; \\\n    true\n",
+    )?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("team.Dockerfile"))
+        .arg("--overlay")
+        .arg(fixture.path("me.Dockerfile"))
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    let path = fixture.dir_path.display();
+    assert_eq!(
+        stdout,
+        format!(
+            "This is a dry run. Add the --go option to execute the below command(s).\n\
+            ---> [flatpak install -y --user flathub org.gimp.GIMP]\n\
+            \x20    layer: \"{path}/me.Dockerfile\"\n"
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn overlay_replacing_a_checked_download() -> anyhow::Result<()> {
+    const SHA256: &str = "8d0729d3a832724cf82652aedd31080f66b19e001a244a18f750879283697f21";
+    let fixture = Fixture::new("overlay_replacing_a_checked_download")?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "team.Dockerfile",
+        format!(
+            "RUN set -eux; \\\n\
+            \x20   curl -fsSL https://example.com/v1/gitalias.txt -o /opt/gitalias; \\\n\
+            \x20   echo \"{SHA256}  /opt/gitalias\" | sha256sum -c; \\\n\
+            \x20   true\n"
+        ),
+    )?;
+    fixture.write(
+        "me.Dockerfile",
+        "RUN set -eux; \\\n\
+        \x20   curl -fsSL https://example.com/v2/gitalias.txt -o /opt/gitalias; \\\n\
+        \x20   true\n",
+    )?;
+    let output = sync_install()
+        .arg(fixture.path("current"))
+        .arg(fixture.path("team.Dockerfile"))
+        .arg("--overlay")
+        .arg(fixture.path("me.Dockerfile"))
+        .output()
+        .context("failed to execute process")?;
+    let status = output.status;
+    ensure!(status.code() == Some(CHANGES_PLANNED_EXIT_CODE), "unexpected status: {status}");
+    let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+    let path = fixture.dir_path.display();
+    assert_eq!(
+        stdout,
+        format!(
+            "This is a dry run. Add the --go option to execute the below command(s).\n\
+            ---> [curl -fsSL https://example.com/v2/gitalias.txt -o /opt/gitalias]\n\
+            \x20    layer: \"{path}/me.Dockerfile\"\n"
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn overlay_commands_get_the_env_instructions_of_the_target_state() -> anyhow::Result<()> {
+    let fixture = Fixture::new("overlay_commands_get_the_env_instructions_of_the_target_state")?;
+    let log_path = fixture.path("log.txt");
+    fixture.write_executable(
+        "pixi",
+        &format!("#!/bin/sh\necho \"$HOME $GIT_VERSION\" >> '{}'\n", log_path.display()),
+    )?;
+    fixture.write("current", "")?;
+    fixture.write(
+        "team.Dockerfile",
+        format!(
+            "ENV PATH=\"{}:$PATH\"\n\
+            ENV HOME=\"/nonexistent_home\"\n\
+            RUN set -eux; \\\n    pixi global install git=2.55.0; \\\n    true\n",
+            fixture.bin_path().display()
+        ),
+    )?;
+    fixture.write(
+        "me.Dockerfile",
+        "ENV GIT_VERSION=2.56.0\n\
+        RUN set -eux; \\\n    pixi global install git=2.56.0; \\\n    true\n\
+        ENV HOME=\"/late_home\"\n",
+    )?;
+    let path = fixture.dir_path.display();
+    for (mode_args, layer_line) in [
+        (&["--keep-going"][..], format!("     layer: \"{path}/me.Dockerfile\"\n")),
+        (&["--jobs", "2"][..], format!("[1]      layer: \"{path}/me.Dockerfile\"\n")),
+    ] {
+        let output = sync_install()
+            .arg(fixture.path("current"))
+            .arg(fixture.path("team.Dockerfile"))
+            .arg("--overlay")
+            .arg(fixture.path("me.Dockerfile"))
+            .arg("--go")
+            .args(mode_args)
+            .output()
+            .context("failed to execute process")?;
+        let status = output.status;
+        ensure!(status.success(), "error status: {status}");
+        let stdout = String::from_utf8(output.stdout).context("non-UTF8 command output")?;
+        ensure!(stdout.contains(&layer_line), "missing layer line: {stdout}");
+    }
+    let log = fs::read_to_string(&log_path).context("failed to read the log file")?;
+    assert_eq!(log, "/nonexistent_home 2.56.0\n/nonexistent_home 2.56.0\n");
+    Ok(())
+}